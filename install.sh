@@ -1,7 +1,12 @@
 #!/bin/sh
 
 if test -d /lib/systemd/system/; then
-	install fwatchd.service /lib/systemd/system
+	install -m 644 fwatchd.service fwatchd.socket /lib/systemd/system
+fi
+
+if test -d /usr/lib/systemd/user/; then
+	install -m 644 fwatchd-user.service /usr/lib/systemd/user/fwatchd.service
+	install -m 644 fwatchd-user.socket /usr/lib/systemd/user/fwatchd.socket
 fi
 
 if ! id -u fwatch >/dev/null; then
@@ -10,3 +15,10 @@ fi
 
 install ./target/release/fwatchctl /usr/sbin
 install ./target/release/fwatchd /usr/sbin
+
+./target/release/fwatchctl man /usr/share/man/man1
+./target/release/fwatchd --man /usr/share/man/man1
+
+if test -d /usr/share/bash-completion/completions/; then
+	./target/release/fwatchctl completions bash > /usr/share/bash-completion/completions/fwatchctl
+fi
@@ -0,0 +1,378 @@
+use crate::socket::{unix_now, Action, Alias, Entry, Snapshot};
+use anyhow::{anyhow, Context, Result};
+use crypto::digest::Digest;
+use crypto::sha2;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path};
+
+/// The index comes first in an archive, followed by the snapshots.
+const ARCHIVE_INDEX: &str = "index.json";
+const SNAPSHOTS_DIR: &str = "snapshots/";
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Entries as stored in an archive, where snapshot paths are names of
+/// archive members rather than paths on the host.
+#[derive(Serialize, Deserialize)]
+struct ArchiveIndex {
+    files: HashMap<String, Entry>,
+}
+
+fn header(size: usize) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size as u64);
+    header.set_mode(0o644);
+    header.set_mtime(unix_now());
+    header.set_cksum();
+    header
+}
+
+/// A tar archive of the entries and the content of their snapshots, as
+/// given by `read`, compressed with zstd if asked to.
+pub fn export<F>(files: HashMap<String, Entry>, compress: bool, read: F) -> Result<Vec<u8>>
+where
+    F: Fn(&Snapshot) -> Result<Vec<u8>>,
+{
+    let mut files = files;
+    // archive member --> stored snapshot
+    let mut members = BTreeMap::new();
+    for entry in files.values_mut() {
+        for (hash, snapshot) in entry.snapshots.iter_mut() {
+            let name = format!("{SNAPSHOTS_DIR}{hash}");
+            members.insert(name.clone(), snapshot.clone());
+            snapshot.path = name;
+            snapshot.commit = None;
+        }
+    }
+
+    let mut builder = tar::Builder::new(vec![]);
+    let index = serde_json::to_vec_pretty(&ArchiveIndex { files })
+        .context("Failed to serialize archive index")?;
+    builder
+        .append_data(&mut header(index.len()), ARCHIVE_INDEX, index.as_slice())
+        .context("Failed to add index to archive")?;
+
+    for (name, snapshot) in &members {
+        let content = read(snapshot)?;
+        builder
+            .append_data(&mut header(content.len()), name, content.as_slice())
+            .context(format!("Failed to add {} to archive", snapshot.path))?;
+    }
+
+    let tar = builder.into_inner().context("Failed to finish archive")?;
+    if compress {
+        zstd::encode_all(tar.as_slice(), 0).context("Failed to compress archive")
+    } else {
+        Ok(tar)
+    }
+}
+
+/// Nothing in an archive is trusted. Paths and hashes make up where
+/// snapshots are unpacked, they must not point outside of the snapshot
+/// directory, and every snapshot must be one of the archive, or it would
+/// refer to any file or commit on the host.
+fn validate(files: &HashMap<String, Entry>) -> Result<()> {
+    for (fpath, entry) in files {
+        let path = Path::new(fpath);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return Err(anyhow!("Invalid path {fpath} in archive"));
+        }
+        for (hash, snapshot) in &entry.snapshots {
+            if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow!("Invalid hash {hash} of {fpath} in archive"));
+            }
+            if snapshot.path != format!("{SNAPSHOTS_DIR}{hash}") || snapshot.commit.is_some() {
+                return Err(anyhow!("Snapshot {hash} of {fpath} is not in the archive"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Metadata of another host is restored without what would grant
+/// privileges, setuid/setgid bits and xattrs other than user ones, such
+/// as file capabilities. Nothing an archive names is run either.
+fn sanitize(files: &mut HashMap<String, Entry>) {
+    for entry in files.values_mut() {
+        entry.action = Action::Save;
+        entry.alias = Alias::Basename;
+        entry.metadata = None;
+        for snapshot in entry.snapshots.values_mut() {
+            if let Some(meta) = snapshot.metadata.as_mut() {
+                meta.mode &= 0o1777;
+                meta.xattrs.retain(|key, _| key.starts_with("user."));
+            }
+        }
+    }
+}
+
+/// Unpack the snapshots of an archive, compressed or not, into `indexd`
+/// with the same layout as snapshots taken by the daemon. The returned
+/// entries refer to the unpacked snapshots. Nothing is left behind of an
+/// archive which fails to import.
+pub fn import(path: &Path, indexd: &str) -> Result<HashMap<String, Entry>> {
+    let mut written = vec![];
+    let res = unpack(path, indexd, &mut written);
+    if res.is_err() {
+        for target in &written {
+            if let Err(e) = std::fs::remove_file(target) {
+                warn!("Failed to remove {}, {}", target, e);
+            }
+        }
+    }
+    res
+}
+
+/// Unpack as [`import`], adding the snapshots which did not exist yet to
+/// `written`.
+fn unpack(path: &Path, indexd: &str, written: &mut Vec<String>) -> Result<HashMap<String, Entry>> {
+    let f = File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(f);
+    let compressed = reader
+        .fill_buf()
+        .context("Failed to read archive")?
+        .starts_with(&ZSTD_MAGIC);
+    let reader: Box<dyn Read> = if compressed {
+        Box::new(zstd::Decoder::with_buffer(reader).context("Failed to decompress archive")?)
+    } else {
+        Box::new(reader)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut files: Option<HashMap<String, Entry>> = None;
+    for member in archive.entries().context("Failed to read archive")? {
+        let mut member = member.context("Failed to read archive")?;
+        let name = member
+            .path()
+            .context("Invalid name in archive")?
+            .display()
+            .to_string();
+
+        if name == ARCHIVE_INDEX {
+            let mut index = serde_json::from_reader::<_, ArchiveIndex>(&mut member)
+                .context("Failed to parse archive index")?;
+            validate(&index.files)?;
+            sanitize(&mut index.files);
+            files = Some(index.files);
+            continue;
+        }
+        let files = files
+            .as_mut()
+            .ok_or_else(|| anyhow!("Archive does not start with {ARCHIVE_INDEX}"))?;
+        let hash = name
+            .strip_prefix(SNAPSHOTS_DIR)
+            .ok_or_else(|| anyhow!("Unexpected {name} in archive"))?
+            .to_string();
+
+        let mut content = vec![];
+        member
+            .read_to_end(&mut content)
+            .context(format!("Failed to read {name} from archive"))?;
+        let mut hasher = sha2::Sha256::new();
+        hasher.input(&content);
+        if hasher.result_str() != hash {
+            return Err(anyhow!("Content of {name} does not match its hash"));
+        }
+        for (fpath, entry) in files.iter_mut() {
+            let snapshot = match entry.snapshots.get_mut(&hash) {
+                Some(snapshot) if snapshot.path == name => snapshot,
+                _ => continue,
+            };
+            let target = format!("{}/{}-{}", indexd, fpath, hash);
+            if let Some(dir) = Path::new(&target).parent() {
+                std::fs::create_dir_all(dir)
+                    .context(format!("Failed to create {}", dir.display()))?;
+            }
+            // Content is stored by hash, it may have been stored before
+            if !Path::new(&target).exists() {
+                std::fs::write(&target, &content).context(format!("Failed to write {target}"))?;
+                written.push(target.clone());
+            }
+            snapshot.path = target;
+        }
+    }
+
+    let files = files.ok_or_else(|| anyhow!("Archive has no {ARCHIVE_INDEX}"))?;
+    for (fpath, entry) in &files {
+        if let Some(hash) = entry
+            .snapshots
+            .iter()
+            .find(|(_, s)| s.path.starts_with(SNAPSHOTS_DIR))
+            .map(|(hash, _)| hash)
+        {
+            return Err(anyhow!("Archive is missing snapshot {hash} of {fpath}"));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn sha256(content: &[u8]) -> String {
+        let mut hasher = sha2::Sha256::new();
+        hasher.input(content);
+        hasher.result_str()
+    }
+
+    fn tar(path: &Path, members: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(vec![]);
+        for (name, content) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        std::fs::write(path, builder.into_inner().unwrap()).unwrap();
+    }
+
+    /// An entry as exported, with an action, alias and metadata which would
+    /// have the daemon run a program or restore a setuid root file.
+    fn entry(hash: &str, path: &str) -> serde_json::Value {
+        json!({
+            "snapshots": {
+                hash: {
+                    "alias": "victim",
+                    "path": path,
+                    "modifier": null,
+                    "metadata": {
+                        "mode": 0o4755,
+                        "uid": 0,
+                        "gid": 0,
+                        "xattrs": { "security.capability": [1, 2, 3], "user.note": [1] }
+                    }
+                }
+            },
+            "action": { "Script": "/bin/sh" },
+            "alias": { "Script": "/bin/sh" },
+            "hash": hash,
+            "metadata": null,
+            "history": []
+        })
+    }
+
+    /// Import an archive of `files` and `members` into `indexd`.
+    fn import_archive(
+        dir: &Path,
+        files: serde_json::Value,
+        members: &[(&str, &[u8])],
+    ) -> Result<HashMap<String, Entry>> {
+        let index = json!({ "files": files }).to_string();
+        let mut all = vec![(ARCHIVE_INDEX, index.as_bytes())];
+        all.extend_from_slice(members);
+        let archive = dir.join("archive.tar");
+        tar(&archive, &all);
+        import(&archive, dir.join("index.d").to_str().unwrap())
+    }
+
+    #[test]
+    fn snapshots_must_be_in_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = json!({ "/tmp/victim": entry("aa", "/etc/hostname") });
+        let err = import_archive(dir.path(), files, &[]).unwrap_err();
+        assert!(err.to_string().contains("not in the archive"), "{}", err);
+        assert!(!dir.path().join("index.d").exists());
+
+        // Nor in git
+        let mut victim = entry("aa", "snapshots/aa");
+        victim["snapshots"]["aa"]["commit"] = json!("0123456789abcdef0123456789abcdef01234567");
+        let files = json!({ "/tmp/victim": victim });
+        let err = import_archive(dir.path(), files, &[("snapshots/aa", b"")]).unwrap_err();
+        assert!(err.to_string().contains("not in the archive"), "{}", err);
+    }
+
+    #[test]
+    fn paths_and_hashes_must_stay_in_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        for (fpath, hash) in [("tmp/victim", "aa"), ("/tmp/../etc/victim", "aa")] {
+            let files = json!({ fpath: entry(hash, &format!("{SNAPSHOTS_DIR}{hash}")) });
+            let err = import_archive(dir.path(), files, &[]).unwrap_err();
+            assert!(err.to_string().contains("Invalid path"), "{}", err);
+        }
+        let files = json!({ "/tmp/victim": entry("../aa", "snapshots/../aa") });
+        let err = import_archive(dir.path(), files, &[]).unwrap_err();
+        assert!(err.to_string().contains("Invalid hash"), "{}", err);
+    }
+
+    #[test]
+    fn content_must_match_its_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = sha256(b"theirs\n");
+        let member = format!("{SNAPSHOTS_DIR}{hash}");
+        let files = json!({ "/tmp/victim": entry(&hash, &member) });
+        let err = import_archive(dir.path(), files, &[(&member, b"other\n")]).unwrap_err();
+        assert!(
+            err.to_string().contains("does not match its hash"),
+            "{}",
+            err
+        );
+    }
+
+    /// Files under `dir`, recursively.
+    fn files_in(dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(files_in(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn failed_import_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let (kept, new) = (b"kept\n", b"new\n");
+        let (kept_hash, new_hash) = (sha256(kept), sha256(new));
+        let existing = dir.path().join(format!("index.d/tmp/kept-{kept_hash}"));
+        std::fs::create_dir_all(existing.parent().unwrap()).unwrap();
+        std::fs::write(&existing, kept).unwrap();
+
+        let (kept_member, new_member) = (
+            format!("{SNAPSHOTS_DIR}{kept_hash}"),
+            format!("{SNAPSHOTS_DIR}{new_hash}"),
+        );
+        let files = json!({
+            "/tmp/kept": entry(&kept_hash, &kept_member),
+            "/tmp/new": entry(&new_hash, &new_member),
+            "/tmp/missing": entry("aa", "snapshots/aa"),
+        });
+        let err = import_archive(
+            dir.path(),
+            files,
+            &[(&kept_member, kept), (&new_member, new)],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("missing snapshot aa"), "{}", err);
+        assert_eq!(files_in(&dir.path().join("index.d")), [existing]);
+    }
+
+    #[test]
+    fn imports_nothing_to_run_nor_privileges() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = b"theirs\n";
+        let hash = sha256(content);
+        let member = format!("{SNAPSHOTS_DIR}{hash}");
+        let files = json!({ "/tmp/victim": entry(&hash, &member) });
+        let files = import_archive(dir.path(), files, &[(&member, content)]).unwrap();
+
+        let entry = &files["/tmp/victim"];
+        assert!(matches!(entry.action, Action::Save), "{:?}", entry.action);
+        assert!(matches!(entry.alias, Alias::Basename), "{:?}", entry.alias);
+        let snapshot = &entry.snapshots[&hash];
+        assert_eq!(std::fs::read(&snapshot.path).unwrap(), content);
+        let meta = snapshot.metadata.as_ref().unwrap();
+        assert_eq!(meta.mode, 0o755);
+        assert_eq!(meta.xattrs.keys().collect::<Vec<_>>(), ["user.note"]);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::socket::{AuditQuery, AuditRecord};
+use anyhow::{Context, Result};
+use log::error;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Append-only trail of changes and restores, one JSON record per line.
+/// The log is rotated to `<path>.1`, `<path>.2`, ... once it grows past
+/// `max_size` bytes, keeping at most `keep` rotated logs.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path, max_size: u64, keep: usize) -> AuditLog {
+        AuditLog {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+        }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        let size = match std::fs::metadata(&self.path) {
+            Ok(meta) => meta.len(),
+            Err(_) => return Ok(()),
+        };
+        if size < self.max_size {
+            return Ok(());
+        }
+
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path).context("Failed to truncate audit log");
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1))
+                    .context(format!("Failed to rotate {}", from.display()))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1)).context("Failed to rotate audit log")
+    }
+
+    fn append(&self, rec: &AuditRecord) -> Result<()> {
+        self.rotate()?;
+
+        let mut line = serde_json::to_string(rec).context("Failed to serialize audit record")?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(line.as_bytes()))
+            .context(format!("Failed to write {}", self.path.display()))
+    }
+
+    /// Record an event, failing to do so is logged but does not fail
+    /// the operation which is being audited.
+    pub fn record(&self, rec: AuditRecord) {
+        if let Err(e) = self.append(&rec) {
+            error!("{:#}", e);
+        }
+    }
+
+    /// All records matching the query, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>> {
+        let mut logs: Vec<PathBuf> = (1..=self.keep).rev().map(|n| self.rotated(n)).collect();
+        logs.push(self.path.clone());
+
+        let mut records = vec![];
+        for log in logs.iter().filter(|l| l.exists()) {
+            let f =
+                std::fs::File::open(log).context(format!("Failed to open {}", log.display()))?;
+            for line in BufReader::new(f).lines() {
+                let line = line.context(format!("Failed to read {}", log.display()))?;
+                match serde_json::from_str::<AuditRecord>(&line) {
+                    Ok(rec) if query.matches(&rec) => records.push(rec),
+                    Ok(_) => {}
+                    Err(e) => error!(
+                        "Skipping malformed audit record in {}, {}",
+                        log.display(),
+                        e
+                    ),
+                }
+            }
+        }
+        Ok(records)
+    }
+}
@@ -0,0 +1,462 @@
+//! `fwatchctl browse`, a terminal UI over the tracked files and the
+//! timeline of their snapshots, built on the same requests as the other
+//! commands.
+use crate::{format_time, list_line};
+use anyhow::{Context, Result};
+use fwatchd::socket::*;
+use fwatchd::Client;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+const FILE_KEYS: &str = "enter: snapshots  F5: reload  q: quit";
+const SNAPSHOT_KEYS: &str =
+    "c/d: content/diff  r: restore  p: pin  t/T: add/remove label  n: note  esc: files  q: quit";
+
+/// A snapshot in the timeline of a file.
+struct Entry {
+    /// When the content was first saved, if the history still says
+    time: Option<u64>,
+    item: ListItem,
+}
+
+impl Entry {
+    fn line(&self) -> String {
+        let snapshot = &self.item.snapshot;
+        let time = self.time.map_or_else(|| "-".repeat(19), format_time);
+        let mut line = format!("{}  {:.12}  {}", time, self.item.hash, snapshot.alias);
+        for label in &snapshot.labels {
+            line.push_str(&format!(" #{label}"));
+        }
+        if snapshot.pinned {
+            line.push_str(" pinned");
+        }
+        if let Some(note) = &snapshot.note {
+            line.push_str(&format!(" \"{note}\""));
+        }
+        if self.item.current {
+            line.push_str(" *");
+        }
+        line
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Files,
+    Snapshots,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Preview {
+    Content,
+    /// The snapshot against the live file
+    Diff,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    Restore,
+    AddLabel,
+    RemoveLabel,
+    Note,
+}
+
+impl Prompt {
+    fn question(self) -> &'static str {
+        match self {
+            Prompt::Restore => "Restore this snapshot? (y/n)",
+            Prompt::AddLabel => "Add label:",
+            Prompt::RemoveLabel => "Remove label:",
+            Prompt::Note => "Note, empty to remove:",
+        }
+    }
+}
+
+struct Browser<'a> {
+    client: &'a Client,
+    focus: Focus,
+    files: Vec<String>,
+    file_state: ListState,
+    /// Timeline of the selected file, newest first
+    timeline: Option<(String, Vec<Entry>)>,
+    snapshot_state: ListState,
+    preview: Preview,
+    /// Preview of the selected snapshot, by hash
+    preview_lines: Option<(String, Preview, Vec<Line<'static>>)>,
+    scroll: u16,
+    prompt: Option<(Prompt, String)>,
+    message: Option<String>,
+}
+
+impl<'a> Browser<'a> {
+    fn new(client: &'a Client) -> Result<Browser<'a>> {
+        let mut browser = Browser {
+            client,
+            focus: Focus::Files,
+            files: vec![],
+            file_state: ListState::default(),
+            timeline: None,
+            snapshot_state: ListState::default(),
+            preview: Preview::Content,
+            preview_lines: None,
+            scroll: 0,
+            prompt: None,
+            message: None,
+        };
+        browser.load_files()?;
+        Ok(browser)
+    }
+
+    fn load_files(&mut self) -> Result<()> {
+        let mut files: Vec<String> = self.client.list("*")?.into_iter().map(|i| i.path).collect();
+        files.sort();
+        files.dedup();
+        self.files = files;
+        self.file_state.select(match self.files.len() {
+            0 => None,
+            n => Some(self.file_state.selected().unwrap_or(0).min(n - 1)),
+        });
+        self.timeline = None;
+        Ok(())
+    }
+
+    fn selected_file(&self) -> Option<&str> {
+        self.file_state
+            .selected()
+            .and_then(|i| self.files.get(i))
+            .map(String::as_str)
+    }
+
+    fn entries(&self) -> &[Entry] {
+        self.timeline.as_ref().map_or(&[], |(_, t)| t.as_slice())
+    }
+
+    fn selected_entry(&self) -> Option<&Entry> {
+        self.snapshot_state
+            .selected()
+            .and_then(|i| self.entries().get(i))
+    }
+
+    fn timeline(&self, fpath: &str) -> Result<Vec<Entry>> {
+        let history = self.client.history(fpath)?;
+        let mut entries: Vec<Entry> = self
+            .client
+            .list(fpath)?
+            .into_iter()
+            .map(|item| Entry {
+                time: history
+                    .iter()
+                    .find(|r| r.hash == item.hash && r.kind == RevisionKind::Saved)
+                    .map(|r| r.time),
+                item,
+            })
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.time));
+        Ok(entries)
+    }
+
+    /// Load the timeline of the selected file, keeping the same snapshot
+    /// selected if it is still there.
+    fn load_timeline(&mut self) {
+        let fpath = match self.selected_file() {
+            Some(fpath) => fpath.to_string(),
+            None => {
+                self.timeline = None;
+                return;
+            }
+        };
+        let selected = self.selected_entry().map(|e| e.item.hash.clone());
+        let entries = self.timeline(&fpath).unwrap_or_else(|e| {
+            self.message = Some(format!("{:#}", e));
+            vec![]
+        });
+        let index = selected
+            .and_then(|hash| entries.iter().position(|e| e.item.hash == hash))
+            .unwrap_or(0);
+        self.snapshot_state
+            .select((!entries.is_empty()).then_some(index));
+        self.timeline = Some((fpath, entries));
+        self.preview_lines = None;
+    }
+
+    fn load_preview(&self) -> Vec<Line<'static>> {
+        let (fpath, hash) = match (self.selected_file(), self.selected_entry()) {
+            (Some(fpath), Some(entry)) => (fpath.to_string(), entry.item.hash.clone()),
+            _ => return vec![],
+        };
+        let version = Version::Hash(hash);
+        let res = match self.preview {
+            Preview::Content => self
+                .client
+                .show(&Cat { fpath, version })
+                .map(|content| String::from_utf8_lossy(&content).into_owned()),
+            Preview::Diff => self.client.diff(&Diff {
+                fpath,
+                from: version,
+                to: None,
+            }),
+        };
+        let text = match res {
+            Ok(text) => text,
+            Err(e) => return vec![Line::styled(e.to_string(), Color::Red)],
+        };
+        text.lines()
+            .map(|line| {
+                let style = match (self.preview, line.chars().next()) {
+                    (Preview::Diff, Some('+')) => Style::new().fg(Color::Green),
+                    (Preview::Diff, Some('-')) => Style::new().fg(Color::Red),
+                    (Preview::Diff, Some('@')) => Style::new().fg(Color::Cyan),
+                    _ => Style::new(),
+                };
+                Line::styled(line.to_string(), style)
+            })
+            .collect()
+    }
+
+    /// Fetch whatever the selection needs before drawing.
+    fn refresh(&mut self) {
+        let fpath = self.selected_file().map(str::to_string);
+        if self.timeline.as_ref().map(|(f, _)| f) != fpath.as_ref() {
+            self.snapshot_state.select(None);
+            self.load_timeline();
+        }
+        if self.focus != Focus::Snapshots {
+            return;
+        }
+        let hash = match self.selected_entry() {
+            Some(entry) => entry.item.hash.clone(),
+            None => return,
+        };
+        let stale = match &self.preview_lines {
+            Some((h, p, _)) => *h != hash || *p != self.preview,
+            None => true,
+        };
+        if stale {
+            let lines = self.load_preview();
+            self.preview_lines = Some((hash, self.preview, lines));
+            self.scroll = 0;
+        }
+    }
+
+    /// Carry out a request on the selected snapshot and show its outcome.
+    fn act(&mut self, prompt: Prompt, input: String) {
+        let (fpath, entry) = match (self.selected_file(), self.selected_entry()) {
+            (Some(fpath), Some(entry)) => (fpath.to_string(), entry),
+            _ => return,
+        };
+        let version = Version::Hash(entry.item.hash.clone());
+        let tag = |add: Vec<String>, remove: Vec<String>, note: Option<String>| Tag {
+            fpath: fpath.clone(),
+            version: version.clone(),
+            add,
+            remove,
+            note,
+        };
+        let res = match prompt {
+            Prompt::Restore if input == "y" => self
+                .client
+                .select(&Select {
+                    fpath: fpath.clone(),
+                    version: version.clone(),
+                    run_hooks: false,
+                })
+                .map(|v| format!("Selected {} {}", v.path, v.hash)),
+            Prompt::Restore => return,
+            Prompt::AddLabel => self
+                .client
+                .tag(&tag(vec![input], vec![], None))
+                .map(|item| list_line(&item)),
+            Prompt::RemoveLabel => self
+                .client
+                .tag(&tag(vec![], vec![input], None))
+                .map(|item| list_line(&item)),
+            Prompt::Note => self
+                .client
+                .tag(&tag(vec![], vec![], Some(input)))
+                .map(|item| list_line(&item)),
+        };
+        self.message = Some(res.unwrap_or_else(|e| e.to_string()));
+        self.load_timeline();
+    }
+
+    fn pin(&mut self) {
+        let (fpath, entry) = match (self.selected_file(), self.selected_entry()) {
+            (Some(fpath), Some(entry)) => (fpath.to_string(), entry),
+            _ => return,
+        };
+        let res = self.client.pin(&Pin {
+            fpath,
+            version: Version::Hash(entry.item.hash.clone()),
+            pinned: !entry.item.snapshot.pinned,
+        });
+        self.message = Some(match res {
+            Ok(item) if item.snapshot.pinned => format!("Pinned {} {}", item.path, item.hash),
+            Ok(item) => format!("Unpinned {} {}", item.path, item.hash),
+            Err(e) => e.to_string(),
+        });
+        self.load_timeline();
+    }
+
+    fn prompt_key(&mut self, key: KeyEvent) {
+        let (prompt, mut input) = match self.prompt.take() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        match key.code {
+            KeyCode::Esc => {}
+            KeyCode::Char(c) if prompt == Prompt::Restore => self.act(prompt, c.to_string()),
+            KeyCode::Enter => self.act(prompt, input),
+            KeyCode::Backspace => {
+                input.pop();
+                self.prompt = Some((prompt, input));
+            }
+            KeyCode::Char(c) => {
+                input.push(c);
+                self.prompt = Some((prompt, input));
+            }
+            _ => self.prompt = Some((prompt, input)),
+        }
+    }
+
+    /// Handle a key press, false when done browsing.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if self.prompt.is_some() {
+            self.prompt_key(key);
+            return true;
+        }
+        self.message = None;
+        let (state, len) = match self.focus {
+            Focus::Files => (&mut self.file_state, self.files.len()),
+            Focus::Snapshots => (
+                &mut self.snapshot_state,
+                self.timeline.as_ref().map_or(0, |(_, t)| t.len()),
+            ),
+        };
+        match key.code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Down | KeyCode::Char('j') if len > 0 => {
+                state.select(Some(state.selected().map_or(0, |i| (i + 1).min(len - 1))))
+            }
+            KeyCode::Up | KeyCode::Char('k') if len > 0 => {
+                state.select(Some(state.selected().map_or(0, |i| i.saturating_sub(1))))
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::F(5) => {
+                if let Err(e) = self.load_files() {
+                    self.message = Some(format!("{:#}", e));
+                }
+            }
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l')
+                if self.focus == Focus::Files && !self.entries().is_empty() =>
+            {
+                self.focus = Focus::Snapshots
+            }
+            KeyCode::Esc | KeyCode::Left | KeyCode::Char('h') if self.focus == Focus::Snapshots => {
+                self.focus = Focus::Files
+            }
+            KeyCode::Esc => return false,
+            _ if self.focus == Focus::Files => {}
+            KeyCode::Char('c') => self.preview = Preview::Content,
+            KeyCode::Char('d') => self.preview = Preview::Diff,
+            KeyCode::Char('p') => self.pin(),
+            KeyCode::Char('r') => self.prompt = Some((Prompt::Restore, String::new())),
+            KeyCode::Char('t') => self.prompt = Some((Prompt::AddLabel, String::new())),
+            KeyCode::Char('T') => self.prompt = Some((Prompt::RemoveLabel, String::new())),
+            KeyCode::Char('n') => {
+                let note = self
+                    .selected_entry()
+                    .and_then(|e| e.item.snapshot.note.clone())
+                    .unwrap_or_default();
+                self.prompt = Some((Prompt::Note, note))
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn block(&self, title: String, focus: Focus) -> Block<'static> {
+        let style = if self.focus == focus {
+            Style::new().fg(Color::Yellow)
+        } else {
+            Style::new()
+        };
+        Block::bordered().title(title).border_style(style)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, preview, status] = Layout::vertical([
+            Constraint::Percentage(40),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [files, snapshots] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)]).areas(top);
+        let highlight = Style::new().add_modifier(Modifier::REVERSED);
+
+        let list = List::new(self.files.clone())
+            .block(self.block(String::from("Tracked files"), Focus::Files))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(list, files, &mut self.file_state);
+
+        let title = match self.selected_file() {
+            Some(fpath) => format!("Snapshots of {fpath}"),
+            None => String::from("Snapshots"),
+        };
+        let list = List::new(self.entries().iter().map(Entry::line))
+            .block(self.block(title, Focus::Snapshots))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(list, snapshots, &mut self.snapshot_state);
+
+        let (title, lines) = match (&self.preview_lines, self.focus) {
+            (Some((hash, Preview::Content, lines)), Focus::Snapshots) => {
+                (format!("Content of {hash:.12}"), lines.clone())
+            }
+            (Some((hash, Preview::Diff, lines)), Focus::Snapshots) => {
+                (format!("{hash:.12} against the live file"), lines.clone())
+            }
+            _ => (String::from("Preview"), vec![]),
+        };
+        let paragraph = Paragraph::new(lines)
+            .block(Block::bordered().title(title))
+            .scroll((self.scroll, 0));
+        frame.render_widget(paragraph, preview);
+
+        let line = match (&self.prompt, &self.message) {
+            (Some((prompt, input)), _) => Line::raw(format!("{} {}", prompt.question(), input)),
+            (None, Some(msg)) => Line::styled(msg.clone(), Color::Yellow),
+            (None, None) if self.focus == Focus::Files => Line::raw(FILE_KEYS),
+            (None, None) => Line::raw(SNAPSHOT_KEYS),
+        };
+        frame.render_widget(line, status);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            self.refresh();
+            terminal
+                .draw(|frame| self.draw(frame))
+                .context("Failed to draw")?;
+            let event = event::read().context("Failed to read from the terminal")?;
+            if let Event::Key(key) = event {
+                if key.kind == KeyEventKind::Press && !self.key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+pub fn browse(client: &Client) -> Result<()> {
+    // Fail before taking over the terminal when the daemon is unreachable
+    let mut browser = Browser::new(client)?;
+    let mut terminal = ratatui::try_init().context("Failed to set up the terminal")?;
+    let res = browser.run(&mut terminal);
+    ratatui::restore();
+    res
+}
@@ -0,0 +1,302 @@
+use crate::socket::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The daemon could not be reached on its socket
+    Connect(PathBuf, std::io::Error),
+    Io(std::io::Error),
+    /// A request or response could not be encoded or decoded
+    Protocol(String),
+    /// The daemon failed to carry out the request
+    Daemon(ErrorKind, String),
+}
+
+impl Error {
+    /// Whether the daemon is not listening on the socket at all.
+    pub fn not_running(&self) -> bool {
+        matches!(self, Error::Connect(_, e) if matches!(
+            e.kind(),
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+        ))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connect(path, _) if self.not_running() => {
+                write!(f, "fwatchd is not running on {}", path.display())
+            }
+            Error::Connect(path, e) => {
+                write!(
+                    f,
+                    "Failed to connect to fwatchd on {}, {}",
+                    path.display(),
+                    e
+                )
+            }
+            Error::Io(e) => write!(f, "Failed to talk to fwatchd, {}", e),
+            Error::Protocol(msg) => write!(f, "Invalid message, {}", msg),
+            Error::Daemon(_, msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Error {
+        match *e {
+            // Such as a timeout, not a malformed message
+            bincode::ErrorKind::Io(e) => Error::Io(e),
+            e => Error::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Protocol(e.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Events received after [`Client::subscribe`], until the daemon closes
+/// the connection.
+pub struct Events {
+    lines: Lines<BufReader<UnixStream>>,
+}
+
+impl Iterator for Events {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        Some(serde_json::from_str(&line).map_err(Error::from))
+    }
+}
+
+/// Controls a running daemon over its socket, one connection per request.
+///
+/// ```no_run
+/// let client = fwatchd::Client::new();
+/// for item in client.list("/etc/hosts")? {
+///     println!("{} {}", item.hash, item.snapshot.alias);
+/// }
+/// # Ok::<(), fwatchd::client::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket: PathBuf,
+    timeout: Option<Duration>,
+}
+
+/// How long a request may block on the socket by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    /// A client of the daemon listening on `$FWATCHD_SOCKET`. When it is
+    /// not set, of the per-user daemon of the user if one is running, or
+    /// else of the system daemon on [`SOCK_PATH`]. A socket left behind by
+    /// a per-user daemon which is gone is not used.
+    pub fn new() -> Client {
+        if let Some(path) = std::env::var_os(SOCK_ENV) {
+            return Client::with_socket(path);
+        }
+        match user_socket() {
+            Some(path) if UnixStream::connect(&path).is_ok() => Client::with_socket(path),
+            _ => Client::with_socket(SOCK_PATH),
+        }
+    }
+
+    pub fn with_socket<P: AsRef<Path>>(socket: P) -> Client {
+        Client {
+            socket: socket.as_ref().to_path_buf(),
+            timeout: Some(DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// Fail requests that block on reading or writing the socket for
+    /// longer than `timeout`, or never with `None`.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn send<T: Serialize>(&self, command: Command, payload: &T) -> Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .map_err(|e| Error::Connect(self.socket.clone(), e))?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        let pkt = Packet {
+            command,
+            payload: bincode::serialize(payload)?,
+        };
+        stream.write_all(&bincode::serialize(&pkt)?)?;
+        Ok(stream)
+    }
+
+    fn response<R: Read>(reader: R) -> Result<Vec<u8>> {
+        match bincode::deserialize_from(reader)? {
+            Response::Ok(response) => Ok(response),
+            Response::Err(kind, msg) => Err(Error::Daemon(kind, msg)),
+        }
+    }
+
+    /// Send a request and read the raw response.
+    pub fn request<T: Serialize>(&self, command: Command, payload: &T) -> Result<Vec<u8>> {
+        Client::response(self.send(command, payload)?)
+    }
+
+    fn message<T: Serialize>(&self, command: Command, payload: &T) -> Result<String> {
+        let response = self.request(command, payload)?;
+        String::from_utf8(response).map_err(|e| Error::Protocol(e.to_string()))
+    }
+
+    fn json<T, R>(&self, command: Command, payload: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let response = self.request(command, payload)?;
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    fn json_lines<T, R>(&self, command: Command, payload: &T) -> Result<Vec<R>>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        self.message(command, payload)?
+            .lines()
+            .map(|line| serde_json::from_str(line).map_err(Error::from))
+            .collect()
+    }
+
+    pub fn echo(&self, msg: &str) -> Result<String> {
+        let response = self.request(Command::Echo, &msg)?;
+        Ok(bincode::deserialize(&response)?)
+    }
+
+    /// Ask the daemon to fail, which makes it respond with `msg` as error.
+    pub fn echo_err(&self, msg: &str) -> Result<String> {
+        self.message(Command::Echoerr, &msg)
+    }
+
+    pub fn status(&self) -> Result<Status> {
+        self.json(Command::Status, &())
+    }
+
+    /// Start tracking a file, returning the snapshot taken of it.
+    pub fn track(&self, track: &Track) -> Result<FileVersion> {
+        self.json(Command::Track, track)
+    }
+
+    /// Snapshots of a tracked file, or of every tracked file for `"*"`.
+    pub fn list(&self, fpath: &str) -> Result<Vec<ListItem>> {
+        self.json_lines(Command::List, &fpath)
+    }
+
+    pub fn history(&self, fpath: &str) -> Result<Vec<Revision>> {
+        self.json_lines(Command::History, &fpath)
+    }
+
+    /// Restore a snapshot, returning the version the file now has.
+    pub fn select(&self, select: &Select) -> Result<FileVersion> {
+        self.json(Command::Select, select)
+    }
+
+    /// Content of a snapshot.
+    pub fn show(&self, cat: &Cat) -> Result<Vec<u8>> {
+        self.request(Command::Cat, cat)
+    }
+
+    pub fn diff(&self, diff: &Diff) -> Result<String> {
+        self.message(Command::Diff, diff)
+    }
+
+    /// Change the labels of a snapshot, returning it as updated.
+    pub fn tag(&self, tag: &Tag) -> Result<ListItem> {
+        self.json(Command::Tag, tag)
+    }
+
+    /// Pin or unpin a snapshot, returning it as updated.
+    pub fn pin(&self, pin: &Pin) -> Result<ListItem> {
+        self.json(Command::Pin, pin)
+    }
+
+    pub fn snapshot(&self, req: &ForceSnapshot) -> Result<Taken> {
+        self.json(Command::Snapshot, req)
+    }
+
+    pub fn checkpoint(&self, req: &NewCheckpoint) -> Result<Taken> {
+        self.json(Command::Checkpoint, req)
+    }
+
+    pub fn checkpoints(&self) -> Result<BTreeMap<String, Checkpoint>> {
+        self.json(Command::Checkpoints, &())
+    }
+
+    /// Restore every file of a checkpoint, returning the versions restored.
+    pub fn restore_checkpoint(&self, req: &RestoreCheckpoint) -> Result<Vec<FileVersion>> {
+        self.json(Command::RestoreCheckpoint, req)
+    }
+
+    /// A tar archive, compressed if asked to.
+    pub fn export(&self, req: &Export) -> Result<Vec<u8>> {
+        self.request(Command::Export, req)
+    }
+
+    /// Import an archive, returning how many snapshots of each file it had.
+    pub fn import(&self, req: &Import) -> Result<BTreeMap<String, usize>> {
+        self.json(Command::Import, req)
+    }
+
+    pub fn audit(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>> {
+        self.json_lines(Command::Audit, query)
+    }
+
+    /// Follow events on paths under any of the filters, or on all
+    /// paths when there are none.
+    pub fn subscribe(&self, filters: &[String]) -> Result<Events> {
+        let stream = self.send(Command::Subscribe, &filters)?;
+        Client::response(&stream)?;
+        // Events may be far apart
+        stream.set_read_timeout(None)?;
+        Ok(Events {
+            lines: BufReader::new(stream).lines(),
+        })
+    }
+}
@@ -0,0 +1,102 @@
+//! Completion of values known only to the running daemon. Completers are
+//! run by the shell through `COMPLETE=<shell> fwatchctl -- <words>`, so any
+//! failure to reach the daemon simply completes nothing.
+use clap_complete::engine::CompletionCandidate;
+use fwatchd::Client;
+use std::ffi::OsStr;
+
+/// The words being completed, after the `--` passed by the shell.
+fn words() -> Vec<String> {
+    std::env::args().skip_while(|a| a != "--").skip(1).collect()
+}
+
+/// The last value given for an option, as `-s VALUE`, `-sVALUE`,
+/// `--long VALUE` or `--long=VALUE`.
+fn option(words: &[String], short: Option<char>, long: &str) -> Option<String> {
+    let long = format!("--{long}");
+    let short = short.map(|s| format!("-{s}"));
+    let mut value = None;
+    let mut iter = words.iter().peekable();
+    while let Some(word) = iter.next() {
+        if *word == long || Some(word) == short.as_ref() {
+            value = iter.peek().map(|v| v.to_string());
+        } else if let Some(v) = word.strip_prefix(&format!("{long}=")) {
+            value = Some(v.to_string());
+        } else if let Some(v) = short.as_ref().and_then(|s| word.strip_prefix(s.as_str())) {
+            if !v.is_empty() && !word.starts_with("--") {
+                value = Some(v.to_string());
+            }
+        }
+    }
+    value
+}
+
+/// A client of the daemon the command line being completed would talk to,
+/// `$FWATCHD_SOCKET` is honoured by [`Client::new`].
+fn client(words: &[String]) -> Client {
+    option(words, None, "socket").map_or_else(Client::new, Client::with_socket)
+}
+
+fn matching(current: &OsStr, candidates: Vec<CompletionCandidate>) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    candidates
+        .into_iter()
+        .filter(|c| {
+            c.get_value()
+                .to_string_lossy()
+                .starts_with(current.as_ref())
+        })
+        .collect()
+}
+
+/// Paths of the tracked files.
+pub fn tracked_files(current: &OsStr) -> Vec<CompletionCandidate> {
+    let mut paths = match client(&words()).list("*") {
+        Ok(items) => items.into_iter().map(|i| i.path).collect::<Vec<_>>(),
+        Err(_) => return vec![],
+    };
+    paths.sort();
+    paths.dedup();
+    let candidates = paths.into_iter().map(CompletionCandidate::new).collect();
+    matching(current, candidates)
+}
+
+/// Hashes of the snapshots of the file given by `--file`, described by
+/// their alias and labels.
+pub fn snapshot_hashes(current: &OsStr) -> Vec<CompletionCandidate> {
+    let words = words();
+    let fpath = match option(&words, Some('f'), "file") {
+        Some(fpath) => fpath,
+        None => return vec![],
+    };
+    let items = match client(&words).list(&fpath) {
+        Ok(items) => items,
+        Err(_) => return vec![],
+    };
+    let candidates = items
+        .into_iter()
+        .map(|i| {
+            let mut help = i.snapshot.alias;
+            for label in &i.snapshot.labels {
+                help.push_str(&format!(" #{label}"));
+            }
+            if i.current {
+                help.push_str(" *");
+            }
+            CompletionCandidate::new(i.hash).help(Some(help.into()))
+        })
+        .collect();
+    matching(current, candidates)
+}
+
+/// Names of the checkpoints.
+pub fn checkpoints(current: &OsStr) -> Vec<CompletionCandidate> {
+    let candidates = match client(&words()).checkpoints() {
+        Ok(checkpoints) => checkpoints
+            .into_iter()
+            .map(|(name, c)| CompletionCandidate::new(name).help(c.message.map(Into::into)))
+            .collect(),
+        Err(_) => return vec![],
+    };
+    matching(current, candidates)
+}
@@ -0,0 +1,77 @@
+use crate::socket::{unix_now, Event, EventKind};
+use log::{debug, error};
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+struct Subscriber {
+    stream: UnixStream,
+    filters: Vec<String>,
+}
+
+impl Subscriber {
+    /// Filters match whole path components, as the policy does, so that
+    /// `/home/a` does not match `/home/ab`.
+    fn wants(&self, path: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|f| Path::new(path).starts_with(f))
+    }
+}
+
+/// Clients which keep their connection open to receive a stream of events.
+#[derive(Default)]
+pub struct Subscribers {
+    subs: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    pub fn add(&mut self, stream: UnixStream, filters: Vec<String>) {
+        // A subscriber which is not keeping up with the event stream
+        // is dropped rather than allowed to stall the event loop.
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("Failed to make subscriber non-blocking, {}", e);
+            return;
+        }
+        self.subs.push(Subscriber { stream, filters });
+    }
+
+    pub fn len(&self) -> usize {
+        self.subs.len()
+    }
+
+    pub fn publish(&mut self, path: &str, kind: EventKind) {
+        if self.subs.is_empty() {
+            return;
+        }
+
+        let event = Event {
+            time: unix_now(),
+            path: path.to_string(),
+            kind,
+        };
+        let mut line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize event {:?}, {}", event, e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        self.subs.retain_mut(|s| {
+            if !s.wants(path) {
+                return true;
+            }
+            match s.stream.write_all(line.as_bytes()) {
+                Ok(_) => true,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    debug!("Dropping slow subscriber");
+                    false
+                }
+                Err(e) => {
+                    debug!("Dropping subscriber, {}", e);
+                    false
+                }
+            }
+        });
+    }
+}
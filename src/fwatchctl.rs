@@ -1,98 +1,492 @@
-mod socket;
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
-use socket::*;
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use clap_complete::engine::ArgValueCompleter;
+use clap_complete::env::{CompleteEnv, Shells};
+use fwatchd::socket::*;
+use fwatchd::Client;
+use std::convert::TryFrom;
 use std::io::prelude::*;
-use std::os::unix::net::UnixStream;
 
-fn track(args: &TrackArgs) -> Result<()> {
+mod browse;
+mod complete;
+
+fn track(client: &Client, args: &TrackArgs) -> Result<()> {
     let track = Track {
         fpath: args.file.clone(),
-        alias: Alias::Basename,
-        action: Action::Save,
+        alias: args.alias.clone().map_or(Alias::Basename, Alias::Script),
+        action: match (&args.script, &args.webhook) {
+            (Some(script), _) => Action::Script(script.clone()),
+            (None, Some(url)) => Action::Webhook(url.clone()),
+            (None, None) => Action::Save,
+        },
     };
-    let payload = bincode::serialize(&track).context("Failed to serialize payload")?;
-    let mut stream = UnixStream::connect(SOCK_PATH)?;
-    let mut response = String::new();
+    let version = client.track(&track)?;
+    println!(
+        "Added {} {} with action {:?} and alias method {:?} to tracked files",
+        version.path, version.hash, track.action, track.alias
+    );
+    Ok(())
+}
+
+fn list_line(item: &ListItem) -> String {
+    let snapshot = &item.snapshot;
+    let mut details = snapshot
+        .modifier
+        .as_ref()
+        .map_or_else(String::new, |m| format!(" [{m}]"));
+    if let Some(commit) = &snapshot.commit {
+        details.push_str(&format!(" git:{commit:.10}"));
+    }
+    for label in &snapshot.labels {
+        details.push_str(&format!(" #{label}"));
+    }
+    if snapshot.pinned {
+        details.push_str(" pinned");
+    }
+    if let Some(note) = &snapshot.note {
+        details.push_str(&format!(" \"{note}\""));
+    }
+    if item.current {
+        details.push_str(" *");
+    }
+    format!(
+        "{} {:32} ({}){}",
+        item.path, item.hash, snapshot.alias, details
+    )
+}
+
+fn list(client: &Client, args: &ListArgs) -> Result<()> {
+    for item in client.list(&args.file)? {
+        println!("{}", list_line(&item));
+    }
+    Ok(())
+}
 
-    let pkt = Packet {
-        command: socket::Command::Track,
-        payload,
+fn status(client: &Client) -> Result<()> {
+    let status = client.status()?;
+    println!("fwatchd {} running as pid {}", status.version, status.pid);
+    println!("started      {}", format_time(status.started));
+    println!("files        {}", status.files);
+    println!("snapshots    {}", status.snapshots);
+    println!("checkpoints  {}", status.checkpoints);
+    println!("subscribers  {}", status.subscribers);
+    println!("store        {}", status.store);
+    Ok(())
+}
+
+fn select(client: &Client, args: &SelectArgs) -> Result<()> {
+    let sel = Select {
+        fpath: args.file.clone(),
+        version: args.version.version()?,
+        run_hooks: args.run_hooks,
     };
-    stream.write_all(&bincode::serialize(&pkt)?)?;
-    stream.read_to_string(&mut response)?;
-    println!("{}", response);
+    let version = client.select(&sel)?;
+    println!("Selected {} {}", version.path, version.hash);
     Ok(())
 }
 
-fn list(args: &ListArgs) -> Result<()> {
-    let payload: String = args.file.clone();
-    let mut stream = UnixStream::connect(SOCK_PATH)?;
-    let mut response = String::new();
-    let payload = bincode::serialize(&payload).context("Failed to serialize payload")?;
-    let pkt = Packet {
-        command: socket::Command::List,
-        payload,
+fn show(client: &Client, args: &ShowArgs) -> Result<()> {
+    let cat = Cat {
+        fpath: args.file.clone(),
+        version: args.version.version()?,
     };
+    write_content(&client.show(&cat)?, &args.output)
+}
 
-    stream.write_all(&bincode::serialize(&pkt)?)?;
-    stream.read_to_string(&mut response)?;
-    println!("{}", response);
+/// Write a response which is file content, rather than a message, to
+/// `output` or stdout.
+fn write_content(response: &[u8], output: &Option<String>) -> Result<()> {
+    match output {
+        Some(out) => std::fs::write(out, response).context(format!("Failed to write {out}")),
+        None => std::io::stdout()
+            .write_all(response)
+            .context("Failed to write to stdout"),
+    }
+}
+
+fn export(client: &Client, args: &ExportArgs) -> Result<()> {
+    let req = Export {
+        files: args.file.clone(),
+        compress: args.zstd
+            || args
+                .output
+                .as_ref()
+                .is_some_and(|out| out.ends_with(".zst")),
+    };
+    write_content(&client.export(&req)?, &args.output)
+}
+
+fn import(client: &Client, args: &ImportArgs) -> Result<()> {
+    // The daemon reads the archive itself
+    let path =
+        std::fs::canonicalize(&args.archive).context(format!("Failed to find {}", args.archive))?;
+    let req = Import {
+        path: path.display().to_string(),
+    };
+    for (fpath, n) in client.import(&req)? {
+        println!("Imported {n} snapshots of {fpath}");
+    }
     Ok(())
 }
 
-fn select(args: &SelectArgs) -> Result<()> {
-    let sel: (String, String) = (args.file.clone(), args.hash.clone());
-    let mut stream = UnixStream::connect(SOCK_PATH).context("Failed to open socket")?;
-    let mut response = String::new();
-    let payload = bincode::serialize(&sel).context("Failed to serialize payload")?;
-    let pkt = Packet {
-        command: socket::Command::Select,
-        payload,
+fn diff(client: &Client, args: &DiffArgs) -> Result<()> {
+    let req = Diff {
+        fpath: args.file.clone(),
+        from: args.version.version()?,
+        to: args.to.clone().map(Version::Hash),
     };
+    print!("{}", client.diff(&req)?);
+    Ok(())
+}
 
-    stream
-        .write_all(&bincode::serialize(&pkt)?)
-        .context("Failed to write to socket")?;
-    stream
-        .read_to_string(&mut response)
-        .context("Failed to read from socket")?;
+fn tag(client: &Client, args: &TagArgs) -> Result<()> {
+    let tag = Tag {
+        fpath: args.file.clone(),
+        version: args.version.version()?,
+        add: args.label.clone(),
+        remove: args.remove.clone(),
+        note: args.note.clone(),
+    };
+    println!("{}", list_line(&client.tag(&tag)?));
+    Ok(())
+}
 
-    println!("{}", response);
+fn pin(client: &Client, args: &PinArgs) -> Result<()> {
+    let pin = Pin {
+        fpath: args.file.clone(),
+        version: args.version.version()?,
+        pinned: !args.unpin,
+    };
+    println!("{}", list_line(&client.pin(&pin)?));
     Ok(())
 }
 
-fn echo(args: &EchoArgs, is_err: bool) -> Result<()> {
-    let msg: String = args.message.clone();
-    let mut stream = UnixStream::connect(SOCK_PATH)?;
-    let mut response = String::new();
-    let payload = bincode::serialize(&msg).context("Failed to serialize payload")?;
-    let pkt = Packet {
-        command: if is_err {
-            socket::Command::Echoerr
-        } else {
-            socket::Command::Echo
-        },
-        payload,
+fn snapshot(client: &Client, args: &SnapshotArgs) -> Result<()> {
+    let req = ForceSnapshot {
+        fpath: args.file.clone(),
+        message: args.message.clone(),
+    };
+    print_taken(&client.snapshot(&req)?);
+    Ok(())
+}
+
+fn print_taken(taken: &Taken) {
+    if let Some(name) = &taken.checkpoint {
+        println!("Checkpoint {} of {} files", name, taken.files.len());
+    }
+    for (fpath, hash) in &taken.files {
+        println!("Saved {fpath} {hash}");
+    }
+}
+
+fn checkpoint(client: &Client, args: &CheckpointArgs) -> Result<()> {
+    let req = NewCheckpoint {
+        name: args.name.clone(),
+        files: args.file.clone(),
+        message: args.message.clone(),
+    };
+    print_taken(&client.checkpoint(&req)?);
+    Ok(())
+}
+
+fn checkpoints(client: &Client) -> Result<()> {
+    for (name, c) in client.checkpoints()? {
+        let message = c.message.map_or_else(String::new, |m| format!(" \"{m}\""));
+        println!("{} {}{}", format_time(c.time), name, message);
+        for (fpath, hash) in c.files {
+            println!("    {} {}", fpath, hash);
+        }
+    }
+    Ok(())
+}
+
+fn restore(client: &Client, args: &RestoreArgs) -> Result<()> {
+    let req = RestoreCheckpoint {
+        name: args.checkpoint.clone(),
+        run_hooks: args.run_hooks,
     };
+    for version in client.restore_checkpoint(&req)? {
+        println!("Restored {} {}", version.path, version.hash);
+    }
+    Ok(())
+}
+
+fn watch(client: &Client, args: &WatchArgs) -> Result<()> {
+    for event in client.subscribe(&args.file)? {
+        let event = event?;
+        if args.json {
+            println!("{}", serde_json::to_string(&event)?);
+            continue;
+        }
+
+        match event.kind {
+            EventKind::Changed => println!("{} {} changed", event.time, event.path),
+            EventKind::Restored { hash } => {
+                println!("{} {} restored {}", event.time, event.path, hash)
+            }
+            EventKind::MetadataChanged => {
+                println!("{} {} metadata changed", event.time, event.path)
+            }
+            EventKind::Saved { hash, alias } => {
+                println!("{} {} saved {} ({})", event.time, event.path, hash, alias)
+            }
+            EventKind::ActionRun(action) => {
+                println!("{} {} ran {:?}", event.time, event.path, action)
+            }
+            EventKind::ActionFailed { action, error } => println!(
+                "{} {} failed {:?}, {}",
+                event.time, event.path, action, error
+            ),
+            EventKind::WatchLost => println!("{} {} watch lost", event.time, event.path),
+        }
+    }
+    Ok(())
+}
+
+/// Parse a local time as `YYYY-MM-DD[ HH:MM[:SS]]`, or seconds since the epoch.
+fn parse_time(s: &str) -> Result<u64> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| anyhow!("Could not parse time {s}, expected YYYY-MM-DD[ HH:MM[:SS]]"))?;
+    let time = Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| anyhow!("No such local time {s}"))?;
+    u64::try_from(time.timestamp()).context("Time is before the epoch")
+}
+
+fn format_time(secs: u64) -> String {
+    i64::try_from(secs)
+        .ok()
+        .and_then(|secs| Local.timestamp_opt(secs, 0).single())
+        .map_or_else(
+            || secs.to_string(),
+            |t| t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        )
+}
+
+fn audit(client: &Client, args: &AuditArgs) -> Result<()> {
+    let query = AuditQuery {
+        path: args.file.clone(),
+        since: args.since.as_deref().map(parse_time).transpose()?,
+        until: args.until.as_deref().map(parse_time).transpose()?,
+    };
+
+    for rec in client.audit(&query)? {
+        if args.json {
+            println!("{}", serde_json::to_string(&rec)?);
+            continue;
+        }
+
+        let peer = match (rec.uid, rec.pid) {
+            (Some(uid), Some(pid)) => format!(" by uid {uid} pid {pid}"),
+            _ => String::new(),
+        };
+        let action = rec
+            .action
+            .map_or_else(String::new, |a| format!(" action {a:?}"));
+        let error = rec
+            .error
+            .map_or_else(String::new, |e| format!(" failed, {e}"));
+        println!(
+            "{} {:?} {} {} -> {}{}{}{}",
+            format_time(rec.time),
+            rec.event,
+            rec.path,
+            rec.old_hash.as_deref().unwrap_or("-"),
+            rec.new_hash.as_deref().unwrap_or("-"),
+            action,
+            peer,
+            error,
+        );
+    }
+    Ok(())
+}
+
+fn history(client: &Client, args: &ListArgs) -> Result<()> {
+    let revs = client.history(&args.file)?;
+    let versions = versions(&revs);
+    for rev in &revs {
+        let n = versions.iter().position(|h| *h == rev.hash).unwrap_or(0) + 1;
+        let kind = match rev.kind {
+            RevisionKind::Saved => "saved",
+            RevisionKind::Restored => "restored",
+        };
+        println!("{} {:>3} {} {}", format_time(rev.time), n, rev.hash, kind);
+    }
+    Ok(())
+}
 
-    stream.write_all(&bincode::serialize(&pkt)?)?;
-    stream.read_to_string(&mut response)?;
+fn echo(client: &Client, args: &EchoArgs, is_err: bool) -> Result<()> {
+    let response = if is_err {
+        client.echo_err(&args.message)?
+    } else {
+        client.echo(&args.message)?
+    };
     println!("{}", response);
     Ok(())
 }
 
+#[derive(Parser, Debug, Clone)]
+#[group(required = true, multiple = false)]
+struct VersionArgs {
+    /// Hash, unique hash prefix, alias or label of the version
+    #[arg(short = 'H', long, add = ArgValueCompleter::new(complete::snapshot_hashes))]
+    hash: Option<String>,
+    /// The version before the current one
+    #[arg(long)]
+    prev: bool,
+    /// The Nth version, as numbered by history
+    #[arg(long)]
+    version: Option<usize>,
+    /// The version current at this time, YYYY-MM-DD[ HH:MM[:SS]]
+    #[arg(long)]
+    at: Option<String>,
+}
+
+impl VersionArgs {
+    fn version(&self) -> Result<Version> {
+        Ok(if let Some(hash) = &self.hash {
+            Version::Hash(hash.clone())
+        } else if let Some(n) = self.version {
+            Version::Number(n)
+        } else if let Some(at) = &self.at {
+            Version::At(parse_time(at)?)
+        } else {
+            Version::Prev
+        })
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 struct SelectArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: String,
+    #[command(flatten)]
+    version: VersionArgs,
+    /// Run the script action of the file after restoring it
+    #[arg(long)]
+    run_hooks: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct ShowArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: String,
+    #[command(flatten)]
+    version: VersionArgs,
+    /// Write the content to OUTPUT instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct DiffArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: String,
+    #[command(flatten)]
+    version: VersionArgs,
+    /// Hash, unique hash prefix, alias or label of the version to compare
+    /// with, instead of the current content
+    #[arg(long, add = ArgValueCompleter::new(complete::snapshot_hashes))]
+    to: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct TagArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: String,
+    #[command(flatten)]
+    version: VersionArgs,
+    /// Add a label, may be repeated
+    #[arg(short, long)]
+    label: Vec<String>,
+    /// Remove a label, may be repeated
+    #[arg(short, long)]
+    remove: Vec<String>,
+    /// Replace the note, an empty note removes it
     #[arg(short, long)]
+    note: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct PinArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
     file: String,
+    #[command(flatten)]
+    version: VersionArgs,
+    /// Allow the snapshot to be pruned again
+    #[arg(long)]
+    unpin: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(group(ArgGroup::new("which").required(true).args(["file", "all"])))]
+struct SnapshotArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: Option<String>,
+    /// Snapshot every tracked file together, as one checkpoint
+    #[arg(long)]
+    all: bool,
+    /// Note attached to the snapshots
     #[arg(short, long)]
-    hash: String,
+    message: Option<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
-struct ListArgs {
+struct CheckpointArgs {
+    #[arg(short, long)]
+    name: String,
+    /// File to include, may be repeated, all tracked files if none are given
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: Vec<String>,
+    /// Note attached to the snapshots
     #[arg(short, long)]
+    message: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct RestoreArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::checkpoints))]
+    checkpoint: String,
+    /// Run the script actions of the files after restoring them
+    #[arg(long)]
+    run_hooks: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct ExportArgs {
+    /// File to include, may be repeated, all tracked files if none are given
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
+    file: Vec<String>,
+    /// Write the archive to OUTPUT instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Compress the archive with zstd, implied by an OUTPUT ending in .zst
+    #[arg(long)]
+    zstd: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct ImportArgs {
+    /// Archive created by export, which must be readable by the daemon
+    archive: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct ListArgs {
+    #[arg(short, long, add = ArgValueCompleter::new(complete::tracked_files))]
     file: String,
 }
 
@@ -100,6 +494,41 @@ struct ListArgs {
 struct TrackArgs {
     #[arg(short, long)]
     file: String,
+    /// Script printing the alias of a new snapshot, instead of the basename
+    #[arg(short, long)]
+    alias: Option<String>,
+    /// Script to run on changes, instead of saving a snapshot
+    #[arg(short, long)]
+    script: Option<String>,
+    /// URL to POST a JSON notification to on changes, after saving a snapshot
+    #[arg(short, long, conflicts_with = "script")]
+    webhook: Option<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct WatchArgs {
+    /// Only show events for FILE and paths under it, may be repeated
+    #[arg(short, long)]
+    file: Vec<String>,
+    /// Print events as JSON lines
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct AuditArgs {
+    /// Only show records for FILE and paths under it
+    #[arg(short, long)]
+    file: Option<String>,
+    /// Only show records at or after this time, YYYY-MM-DD[ HH:MM[:SS]]
+    #[arg(long)]
+    since: Option<String>,
+    /// Only show records at or before this time, YYYY-MM-DD[ HH:MM[:SS]]
+    #[arg(long)]
+    until: Option<String>,
+    /// Print records as JSON lines
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -108,32 +537,159 @@ struct EchoArgs {
     message: String,
 }
 
+#[derive(Parser, Debug, Clone)]
+struct CompletionsArgs {
+    #[arg(value_parser = Shells::builtins().names().collect::<Vec<_>>())]
+    shell: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct ManArgs {
+    /// Directory to write the pages to
+    dir: String,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 enum CtlCommand {
     Track(TrackArgs),
     List(ListArgs),
     Select(SelectArgs),
+    /// Print the content of a version without restoring it
+    Show(ShowArgs),
+    /// Show the changes since a version
+    Diff(DiffArgs),
+    /// Label a version or attach a note to it
+    Tag(TagArgs),
+    /// Keep a version from being pruned
+    Pin(PinArgs),
+    /// Snapshot files now, regardless of changes
+    Snapshot(SnapshotArgs),
+    /// Snapshot a set of files as a named checkpoint
+    Checkpoint(CheckpointArgs),
+    /// List checkpoints
+    Checkpoints,
+    /// Restore every file of a checkpoint, or none of them
+    Restore(RestoreArgs),
+    /// Write tracked files and their snapshots to an archive
+    Export(ExportArgs),
+    /// Merge an archive created by export into the tracked files
+    Import(ImportArgs),
+    /// Show what the daemon is doing
+    Status,
+    Watch(WatchArgs),
+    Audit(AuditArgs),
+    History(ListArgs),
     Echo(EchoArgs),
     EchoErr(EchoArgs),
+    /// Browse tracked files and their snapshots, and restore, pin or label them
+    Browse,
+    /// Print a script completing commands, tracked files, hashes and
+    /// checkpoints for SHELL, which asks the running daemon while completing
+    Completions(CompletionsArgs),
+    /// Write man pages of fwatchctl and its commands
+    #[command(hide = true)]
+    Man(ManArgs),
 }
 
+const EXIT_STATUS: &str = "\
+Exit status:
+  0  success
+  1  any other failure
+  2  invalid usage
+  3  fwatchd is not running
+  4  permission denied
+  5  the file is not tracked
+  6  no such version of the file";
+
 #[derive(Parser, Debug)]
-#[clap(about, version, author)]
+#[clap(name = "fwatchctl", about, version, author, after_help = EXIT_STATUS)]
 struct Args {
+    /// Control socket of the daemon, defaults to that of the per-user daemon
+    /// if one is running, or else to that of the system daemon
+    #[arg(long, global = true, env = SOCK_ENV)]
+    socket: Option<String>,
     #[command(subcommand)]
     command: CtlCommand,
 }
 
+/// The exit status for a failure, as listed in [`EXIT_STATUS`].
+fn exit_code(e: &anyhow::Error) -> i32 {
+    let err = match e.chain().find_map(|c| c.downcast_ref::<fwatchd::Error>()) {
+        Some(err) => err,
+        None => return 1,
+    };
+    match err {
+        _ if err.not_running() => 3,
+        fwatchd::Error::Connect(_, e) if e.kind() == std::io::ErrorKind::PermissionDenied => 4,
+        fwatchd::Error::Daemon(kind, _) => match kind {
+            ErrorKind::PermissionDenied => 4,
+            ErrorKind::NotTracked => 5,
+            ErrorKind::BadVersion => 6,
+            _ => 1,
+        },
+        _ => 1,
+    }
+}
+
+/// The registration script of the shell, which calls back into fwatchctl
+/// for every completion.
+fn completions(args: &CompletionsArgs) -> Result<()> {
+    let shells = Shells::builtins();
+    let shell = shells
+        .completer(&args.shell)
+        .ok_or_else(|| anyhow!("Unsupported shell {}", args.shell))?;
+    shell
+        .write_registration(
+            "COMPLETE",
+            "fwatchctl",
+            "fwatchctl",
+            "fwatchctl",
+            &mut std::io::stdout(),
+        )
+        .context("Failed to write completions")
+}
+
+fn man(args: &ManArgs) -> Result<()> {
+    std::fs::create_dir_all(&args.dir).context(format!("Failed to create {}", args.dir))?;
+    clap_mangen::generate_to(Args::command(), &args.dir)
+        .context(format!("Failed to write man pages to {}", args.dir))
+}
+
 fn main() {
+    CompleteEnv::with_factory(Args::command).complete();
     let app = Args::parse();
-    match app.command {
-        CtlCommand::Track(args) => track(&args),
-        CtlCommand::Select(args) => select(&args),
-        CtlCommand::List(args) => list(&args),
-        CtlCommand::Echo(args) => echo(&args, false),
-        CtlCommand::EchoErr(args) => echo(&args, true),
+    let client = app
+        .socket
+        .as_ref()
+        .map_or_else(Client::new, Client::with_socket);
+    let res = match app.command {
+        CtlCommand::Track(args) => track(&client, &args),
+        CtlCommand::Select(args) => select(&client, &args),
+        CtlCommand::Show(args) => show(&client, &args),
+        CtlCommand::Diff(args) => diff(&client, &args),
+        CtlCommand::Tag(args) => tag(&client, &args),
+        CtlCommand::Pin(args) => pin(&client, &args),
+        CtlCommand::Snapshot(args) => snapshot(&client, &args),
+        CtlCommand::Checkpoint(args) => checkpoint(&client, &args),
+        CtlCommand::Checkpoints => checkpoints(&client),
+        CtlCommand::Restore(args) => restore(&client, &args),
+        CtlCommand::Export(args) => export(&client, &args),
+        CtlCommand::Import(args) => import(&client, &args),
+        CtlCommand::List(args) => list(&client, &args),
+        CtlCommand::Status => status(&client),
+        CtlCommand::Watch(args) => watch(&client, &args),
+        CtlCommand::Audit(args) => audit(&client, &args),
+        CtlCommand::History(args) => history(&client, &args),
+        CtlCommand::Echo(args) => echo(&client, &args, false),
+        CtlCommand::EchoErr(args) => echo(&client, &args, true),
+        CtlCommand::Browse => browse::browse(&client),
+        CtlCommand::Completions(args) => completions(&args),
+        CtlCommand::Man(args) => man(&args),
         #[allow(unreachable_patterns)]
         _ => Err(anyhow!("Unrecognized command")),
+    };
+    if let Err(e) = res {
+        eprintln!("fwatchctl: {:#}", e);
+        std::process::exit(exit_code(&e));
     }
-    .unwrap();
 }
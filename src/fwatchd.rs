@@ -30,14 +30,27 @@
 //! fwatchctl list
 //! ```
 
-mod socket;
+mod archive;
+mod audit;
+mod events;
+mod metadata;
+mod metrics;
+mod policy;
+mod server;
+mod store;
+mod systemd;
+mod watcher;
+mod webhook;
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use audit::AuditLog;
+use clap::{CommandFactory, Parser};
 use crypto::digest::Digest;
 use crypto::sha2;
 use daemonize::Daemonize;
-use inotify::{EventMask, Inotify, WatchMask};
+use events::Subscribers;
+use fwatchd::socket;
 use log::{debug, error, info, warn, Level, LevelFilter};
+use metrics::Metrics;
 #[cfg(target_os = "macos")]
 use nix::poll::poll;
 #[cfg(target_os = "linux")]
@@ -45,60 +58,98 @@ use nix::poll::ppoll;
 use nix::poll::{PollFd, PollFlags};
 #[cfg(target_os = "linux")]
 use nix::sys::signal::SigSet;
+#[cfg(target_os = "linux")]
+use nix::sys::time::TimeSpec;
 use nix::unistd::{chown, unlink, Gid, Uid};
+use policy::Policy;
 use serde::{Deserialize, Serialize};
+use server::{Peer, Request, Server};
 use signal_hook::flag;
+use signal_hook::low_level::pipe;
 use socket::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::CString;
 use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+use store::Store;
 use syslog::{BasicLogger, Facility, Formatter3164};
+use watcher::{Change, ChangeKind, Watcher};
+use webhook::Outbox;
 
-const INDEX: &str = "/var/run/fwatch/index";
-const INDEXD: &str = "/var/run/fwatch/index.d";
+/// How long after writing a file the daemon recognises events as its own
+const OWN_WRITE_WINDOW: Duration = Duration::from_secs(2);
 
 #[derive(Clone, Serialize, Deserialize)]
 struct State {
     files: HashMap<String, Entry>,
+    // name --> checkpoint
+    #[serde(default)]
+    checkpoints: BTreeMap<String, Checkpoint>,
+    /// Where the index is saved, within the working directory
+    #[serde(skip)]
+    path: String,
 }
 
 impl State {
     fn load(f: &str) -> Result<State> {
-        let state = serde_json::from_reader::<std::fs::File, Self>(
+        let mut state = serde_json::from_reader::<std::fs::File, Self>(
             std::fs::File::open(f).context("Could not open file")?,
         )?;
+        state.path = f.to_string();
 
         Ok(state)
     }
 
-    fn save(&self, path: &str) -> Result<State> {
+    fn save(&self) -> Result<State> {
+        let start = Instant::now();
         let json = serde_json::to_string_pretty(&self).context("Failed to serialize state")?;
-        std::fs::File::create(path)
+        std::fs::File::create(&self.path)
             .and_then(|mut f| f.write_all(json.as_bytes()))
-            .context(format!("Failed to save file {path}"))?;
+            .context(format!("Failed to save file {}", self.path))?;
+        metrics::index_saved(start.elapsed());
 
         Ok(self.clone())
     }
 
-    fn new() -> State {
+    fn new(path: &str) -> State {
         State {
             files: HashMap::new(),
+            checkpoints: BTreeMap::new(),
+            path: path.to_string(),
         }
     }
 }
 
-impl Default for State {
-    fn default() -> Self {
-        Self::new()
-    }
+fn load_index(workdir: &str) -> State {
+    let path = format!("{workdir}/index");
+    State::load(&path).unwrap_or_else(|_| State::new(&path))
 }
 
-fn load_index(workdir: &str) -> State {
-    State::load(&format!("{workdir}/index")).unwrap_or_else(|_| State::new())
+/// Snapshots are stored here unless they are stored in git, and imported
+/// here regardless.
+fn index_dir(workdir: &str) -> String {
+    format!("{workdir}/index.d")
+}
+
+/// Everything the request handlers and actions operate on.
+struct Daemon {
+    state: State,
+    policy: Option<Policy>,
+    subs: Subscribers,
+    audit: AuditLog,
+    // path --> hash written by the daemon itself, and when
+    own_writes: HashMap<String, (String, Instant)>,
+    store: Store,
+    max_snapshots: usize,
+    started: u64,
+    indexd: String,
+    metrics: Metrics,
+    outbox: Outbox,
 }
 
 #[derive(Parser, Debug)]
@@ -113,15 +164,68 @@ struct Args {
     user: String,
     #[clap(short, long, default_value = "fwatch")]
     group: String,
-    #[clap(short, long, default_value = "/var/run/fwatch")]
-    working_directory: String,
+    /// Defaults to /var/run/fwatch, or $XDG_DATA_HOME/fwatch with --per-user
+    #[clap(short, long)]
+    working_directory: Option<String>,
     #[clap(long)]
     foreground: bool,
+    /// Control socket to listen on, which must not be in use by another instance.
+    /// Defaults to /var/run/fwatchd.socket, or $XDG_RUNTIME_DIR/fwatchd.socket
+    /// with --per-user
+    #[clap(long, env = SOCK_ENV)]
+    socket: Option<String>,
+    /// Run as the invoking user, to track files of that user, rather than
+    /// dropping privileges to --user and --group
+    #[clap(long)]
+    per_user: bool,
+    /// Seconds to wait for a client to send its request or read the response
+    #[clap(long, default_value = "5")]
+    client_timeout: u64,
+    /// JSON policy restricting which peers may issue which requests, on which paths
+    #[clap(long)]
+    policy: Option<String>,
+    /// Audit log, defaults to audit.log in the working directory
+    #[clap(long)]
+    audit_log: Option<String>,
+    /// Size in bytes at which the audit log is rotated
+    #[clap(long, default_value = "10485760")]
+    audit_max_size: u64,
+    /// Number of rotated audit logs to keep
+    #[clap(long, default_value = "5")]
+    audit_keep: usize,
+    /// Watch using fanotify to record which process modified a file, requires CAP_SYS_ADMIN
+    #[clap(long)]
+    fanotify: bool,
+    /// Keep at most this many unpinned snapshots of each file, 0 keeps all
+    #[clap(long, default_value = "0")]
+    max_snapshots: usize,
+    /// Store snapshots as commits in this bare git repository, which is
+    /// created if missing
+    #[clap(long)]
+    git: Option<String>,
+    /// Write the man page to this directory and exit
+    #[clap(long, hide = true)]
+    man: Option<String>,
+    /// Serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9469
+    #[clap(long)]
+    metrics_listen: Option<String>,
+    /// Write Prometheus metrics to this file, for the node_exporter textfile collector
+    #[clap(long)]
+    metrics_file: Option<String>,
+    /// File holding the key webhook notifications are signed with
+    #[clap(long)]
+    webhook_secret_file: Option<String>,
+    /// Times a webhook notification is retried before it is dropped
+    #[clap(long, default_value = "10")]
+    webhook_retries: u32,
 }
 
-fn script(fpath: &str, spath: &str) -> Result<()> {
+/// Scripts are told what caused them to run through FWATCHD_EVENT, which is
+/// either "change" or "restore".
+fn script(fpath: &str, spath: &str, event: &str) -> Result<()> {
     std::process::Command::new(spath)
         .arg(fpath)
+        .env("FWATCHD_EVENT", event)
         .spawn()
         .context(format!("Failed to execute {}", spath))?;
 
@@ -138,7 +242,15 @@ fn sha256sum(fpath: &Path) -> Result<String> {
     Ok(hasher.result_str())
 }
 
-fn save(state: &mut State, fname: &str, alias: &Alias) -> Result<()> {
+/// Snapshot a file, `event` is what caused the snapshot to be taken.
+fn save(
+    state: &mut State,
+    store: &Store,
+    fname: &str,
+    alias: &Alias,
+    modifier: Option<Modifier>,
+    event: &str,
+) -> Result<(String, String)> {
     let fpath = std::path::Path::new(&fname);
 
     let astr = match alias.clone() {
@@ -159,247 +271,1277 @@ fn save(state: &mut State, fname: &str, alias: &Alias) -> Result<()> {
         .to_string(),
     };
     let hash = sha256sum(fpath)?;
-    let target = format!("{}/{}-{}", INDEXD, fpath.display(), &hash);
-    std::fs::create_dir_all(
-        std::path::Path::new(&target)
-            .parent()
-            .context("Failed to create directory for target")
-            .unwrap(),
-    )?;
-    std::fs::copy(fpath, &target).context("Failed to save file version")?;
-    state
+    let metadata = match metadata::capture(fpath) {
+        Ok(m) => Some(m),
+        Err(e) => {
+            warn!("{:#}", e);
+            None
+        }
+    };
+    let mut message = format!("{event} {fname}\n\nAlias: {astr}\nHash: {hash}\n");
+    if let Some(m) = &modifier {
+        message.push_str(&format!("Modifier: {m}\n"));
+    }
+    let (target, commit) = store.put(fname, &hash, &message)?;
+    let entry = state
         .files
         .entry(fpath.display().to_string())
         .or_insert(Entry {
             action: Action::Save,
             alias: alias.clone(),
             snapshots: HashMap::default(),
-        })
+            hash: None,
+            metadata: None,
+            history: vec![],
+        });
+    entry.metadata = metadata.clone();
+    if entry.history.last().map(|r| &r.hash) != Some(&hash) {
+        entry.history.push(Revision {
+            time: unix_now(),
+            hash: hash.clone(),
+            kind: RevisionKind::Saved,
+        });
+    }
+    // Labels and pins of a version which is saved again are kept
+    let (labels, note, pinned) = entry
         .snapshots
-        .insert(hash, (astr, target));
+        .get(&hash)
+        .map_or_else(Default::default, |s| {
+            (s.labels.clone(), s.note.clone(), s.pinned)
+        });
+    entry.snapshots.insert(
+        hash.clone(),
+        Snapshot {
+            alias: astr.clone(),
+            path: target,
+            commit,
+            modifier,
+            metadata,
+            labels,
+            note,
+            pinned,
+        },
+    );
 
-    state.save(INDEX)?;
-    Ok(())
+    state.save()?;
+    Ok((hash, astr))
+}
+
+/// Replace the file with the snapshot through a temporary file in the same
+/// directory and a rename, so that readers never see a partially written file.
+fn restore(store: &Store, snapshot: &Snapshot, fpath: &Path) -> Result<()> {
+    // Replace the target of a symlink rather than the symlink itself
+    let fpath = std::fs::canonicalize(fpath).unwrap_or_else(|_| fpath.to_path_buf());
+    let dir = fpath
+        .parent()
+        .context("Could not determine directory of target")?;
+    let name = fpath
+        .file_name()
+        .context("Could not determine basename")?
+        .to_string_lossy();
+    let tmp = dir.join(format!(".{}.fwatchd-{}", name, std::process::id()));
+    // Without recorded metadata, keep that of the file being replaced
+    let meta = match &snapshot.metadata {
+        Some(meta) => Some(meta.clone()),
+        None => metadata::capture(&fpath).ok(),
+    };
+
+    let res = store
+        .read(snapshot)
+        .and_then(|content| {
+            // Private until the metadata is applied, the content may be
+            // that of a file which is not readable by everyone
+            let mut dst = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&tmp)
+                .context(format!("Failed to create {}", tmp.display()))?;
+            dst.write_all(&content)
+                .context("Failed to write snapshot")?;
+            dst.sync_all().context("Failed to sync snapshot")?;
+            match &meta {
+                Some(meta) => metadata::apply(&tmp, meta),
+                None => Ok(()),
+            }
+        })
+        .and_then(|_| {
+            std::fs::rename(&tmp, &fpath)
+                .context(format!("Failed to rename into {}", fpath.display()))
+        });
+
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        return res;
+    }
+    std::fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context(format!("Failed to sync {}", dir.display()))
 }
 
 fn echoerr(pkt: &Packet) -> Result<Vec<u8>> {
-    let msg = bincode::deserialize::<String>(&pkt.payload).context("Failed to deserialize")?;
+    let msg = pkt.decode::<String>().map_err(invalid)?;
     Err(anyhow!(msg))
 }
 
 fn echo(pkt: &Packet) -> Result<Vec<u8>> {
-    let msg = bincode::deserialize::<String>(&pkt.payload).context("Failed to deserialize")?;
+    let msg = pkt.decode::<String>().map_err(invalid)?;
     Ok(bincode::serialize(&msg)?)
 }
 
+fn respond_json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).context("Failed to serialize response")
+}
+
+fn list_item(fname: &str, hash: &str, snapshot: &Snapshot) -> ListItem {
+    let current = sha256sum(Path::new(fname)).ok();
+    ListItem {
+        path: fname.to_string(),
+        hash: hash.to_string(),
+        snapshot: snapshot.clone(),
+        current: current.as_deref() == Some(hash),
+    }
+}
+
+fn list_items(fname: &str, entry: &Entry) -> Vec<u8> {
+    let current = sha256sum(Path::new(fname)).ok();
+    let mut resp = vec![];
+    for (hash, snapshot) in &entry.snapshots {
+        let item = ListItem {
+            path: fname.to_string(),
+            hash: hash.clone(),
+            snapshot: snapshot.clone(),
+            current: current.as_ref() == Some(hash),
+        };
+        match serde_json::to_vec(&item) {
+            Ok(mut line) => {
+                resp.append(&mut line);
+                resp.push(b'\n');
+            }
+            Err(e) => error!("Failed to serialize {} {}, {}", fname, hash, e),
+        }
+    }
+    resp
+}
+
 fn list(state: &State, pkt: &Packet) -> Result<Vec<u8>> {
-    let fname = bincode::deserialize::<String>(&pkt.payload).context("Failed to deserialize")?;
+    let fname = pkt.decode::<String>().map_err(invalid)?;
     let resp = match fname.as_str() {
         "*" => {
             let mut tmp = vec![];
             for (k, v) in &state.files {
-                for (ks, vs) in &v.snapshots {
-                    let selected = if let Ok(hash) = sha256sum(Path::new(&k)) {
-                        hash == *ks
-                    } else {
-                        false
-                    };
-                    if selected {
-                        tmp.append(&mut format!("{k} {ks:32} ({}) *\n", vs.0).as_bytes().to_vec());
-                    } else {
-                        tmp.append(&mut format!("{k} {ks:32} ({})\n", vs.0).as_bytes().to_vec());
-                    }
-                }
+                tmp.append(&mut list_items(k, v));
             }
             tmp
         }
         _ => {
-            let h = state
-                .files
-                .get(&fname)
-                .context("Found no such tracked file")?;
+            let h = state.files.get(&fname).ok_or_else(|| not_tracked(&fname))?;
+            list_items(&fname, h)
+        }
+    };
+    Ok(resp)
+}
 
-            let mut tmp = vec![];
-            for (ks, vs) in &h.snapshots {
-                let selected = if let Ok(hash) = sha256sum(Path::new(&fname)) {
-                    hash == *ks
-                } else {
-                    false
-                };
-                if selected {
-                    tmp.append(
-                        &mut format!("{fname} {ks:32} ({}) *\n", vs.0)
-                            .as_bytes()
-                            .to_vec(),
-                    );
-                } else {
-                    tmp.append(&mut format!("{fname} {ks:32} ({})\n", vs.0).as_bytes().to_vec());
+fn status(d: &Daemon) -> Result<Vec<u8>> {
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        pid: std::process::id(),
+        started: d.started,
+        files: d.state.files.len(),
+        snapshots: d.state.files.values().map(|e| e.snapshots.len()).sum(),
+        checkpoints: d.state.checkpoints.len(),
+        subscribers: d.subs.len(),
+        store: d.store.to_string(),
+    };
+    serde_json::to_vec(&status).context("Failed to serialize status")
+}
+
+fn not_tracked(fpath: &str) -> anyhow::Error {
+    Failure::new(ErrorKind::NotTracked, format!("{fpath} is not tracked")).into()
+}
+
+fn invalid(e: bincode::Error) -> anyhow::Error {
+    Failure::new(ErrorKind::InvalidRequest, format!("Invalid request, {e}")).into()
+}
+
+fn snapshot_of<'a>(entry: &'a Entry, hash: &str) -> Result<&'a Snapshot> {
+    entry
+        .snapshots
+        .get(hash)
+        .ok_or_else(|| anyhow!("Found no snapshot {hash}"))
+}
+
+/// The hash of the snapshot a version refers to.
+fn resolve(entry: &Entry, version: &Version) -> Result<String> {
+    find_version(entry, version)
+        .map_err(|e| Failure::new(ErrorKind::BadVersion, format!("{:#}", e)).into())
+}
+
+fn find_version(entry: &Entry, version: &Version) -> Result<String> {
+    let hash = match version {
+        Version::Hash(s) if entry.snapshots.contains_key(s) => s.clone(),
+        Version::Hash(s) => {
+            let mut candidates: Vec<&String> = entry
+                .snapshots
+                .iter()
+                .filter(|(hash, snapshot)| {
+                    hash.starts_with(s.as_str())
+                        || snapshot.alias == *s
+                        || snapshot.labels.contains(s)
+                })
+                .map(|(hash, _)| hash)
+                .collect();
+            candidates.sort();
+            match candidates.as_slice() {
+                [] => return Err(anyhow!("Found no such file version {s}")),
+                [hash] => (*hash).clone(),
+                _ => {
+                    let lines: Vec<String> = candidates
+                        .iter()
+                        .map(|hash| format!("{hash} ({})", entry.snapshots[*hash].alias))
+                        .collect();
+                    return Err(anyhow!(
+                        "{s} matches several versions:\n{}",
+                        lines.join("\n")
+                    ));
                 }
             }
-            tmp
         }
+        Version::Prev => {
+            let current = entry
+                .hash
+                .as_ref()
+                .or_else(|| entry.history.last().map(|rev| &rev.hash));
+            entry
+                .history
+                .iter()
+                .rev()
+                .map(|rev| &rev.hash)
+                .find(|hash| Some(*hash) != current && entry.snapshots.contains_key(*hash))
+                .context("Found no previous version")?
+                .clone()
+        }
+        Version::Number(n) => {
+            let versions = versions(&entry.history);
+            n.checked_sub(1)
+                .and_then(|i| versions.get(i))
+                .context(format!(
+                    "Found no version {n}, there are {} versions",
+                    versions.len()
+                ))?
+                .to_string()
+        }
+        Version::At(time) => entry
+            .history
+            .iter()
+            .rev()
+            .find(|rev| rev.time <= *time)
+            .context("Found no version current at that time")?
+            .hash
+            .clone(),
     };
-    Ok(resp)
+
+    if !entry.snapshots.contains_key(&hash) {
+        return Err(anyhow!("Found no snapshot of version {hash}"));
+    }
+    Ok(hash)
+}
+
+/// Snapshot the current content of a file which is about to be replaced,
+/// it may never have been saved.
+fn save_current(state: &mut State, store: &Store, fpath: &str) -> Result<Option<String>> {
+    if !Path::new(fpath).exists() {
+        return Ok(None);
+    }
+    let alias = state
+        .files
+        .get(fpath)
+        .ok_or_else(|| not_tracked(fpath))?
+        .alias
+        .clone();
+    let (hash, _) = save(state, store, fpath, &alias, None, "pre-restore").context(format!(
+        "Failed to save current content of {fpath} before restoring"
+    ))?;
+    Ok(Some(hash))
+}
+
+/// Record that a file was restored from a snapshot and run its hook.
+fn restored(d: &mut Daemon, fpath: &str, hash: &str, snapshot: &Snapshot, run_hooks: bool) {
+    d.subs.publish(
+        fpath,
+        EventKind::Restored {
+            hash: hash.to_string(),
+        },
+    );
+
+    let entry = match d.state.files.get_mut(fpath) {
+        Some(entry) => entry,
+        None => return,
+    };
+    let old_hash = entry.hash.replace(hash.to_string());
+    if snapshot.metadata.is_some() {
+        entry.metadata = snapshot.metadata.clone();
+    }
+    entry.history.push(Revision {
+        time: unix_now(),
+        hash: hash.to_string(),
+        kind: RevisionKind::Restored,
+    });
+
+    let action = entry.action.clone();
+    // The content is already stored, but the restore is an event of its own in git
+    let message = format!("restore {fpath}\n\nHash: {hash}\n");
+    if let Err(e) = d.store.put(fpath, hash, &message) {
+        warn!("{:#}", e);
+    }
+
+    let res = match (run_hooks, &action) {
+        (true, Action::Script(spath)) => script(fpath, spath, "restore"),
+        (true, Action::Webhook(url)) => {
+            webhook(d, fpath, url, "restore", old_hash.as_deref(), Some(hash))
+        }
+        _ => return,
+    };
+    d.metrics.action(&action, res.is_ok());
+    match res {
+        Ok(_) => d.subs.publish(fpath, EventKind::ActionRun(action.clone())),
+        Err(e) => {
+            error!("{:#}", e);
+            d.subs.publish(
+                fpath,
+                EventKind::ActionFailed {
+                    action: action.clone(),
+                    error: format!("{:#}", e),
+                },
+            )
+        }
+    }
 }
 
-fn select(state: &State, pkt: &Packet) -> Result<Vec<u8>> {
-    let (fpath, hash) =
-        bincode::deserialize::<(String, String)>(&pkt.payload).context("Failed to deserialize")?;
+/// Lines changed from a snapshot to the current content of the file.
+fn diff_summary(d: &Daemon, fpath: &str, from: &str) -> Result<DiffSummary> {
+    let entry = d.state.files.get(fpath).ok_or_else(|| not_tracked(fpath))?;
+    let old = d.store.read(snapshot_of(entry, from)?)?;
+    let new = std::fs::read(fpath).context(format!("Failed to read {fpath}"))?;
+    let patch = git2::Patch::from_buffers(&old, None, &new, None, None)
+        .context("Failed to diff versions")?;
+    let (_, added, removed) = patch.line_stats().context("Failed to diff versions")?;
+    Ok(DiffSummary { added, removed })
+}
+
+/// Queue a notification of the change of a file for the webhook.
+fn webhook(
+    d: &Daemon,
+    fpath: &str,
+    url: &str,
+    event: &str,
+    old_hash: Option<&str>,
+    new_hash: Option<&str>,
+) -> Result<()> {
+    let diff = old_hash.and_then(|old| match diff_summary(d, fpath, old) {
+        Ok(diff) => Some(diff),
+        Err(e) => {
+            warn!("{:#}", e);
+            None
+        }
+    });
+    d.outbox.send(
+        url,
+        &Notification {
+            event: event.to_string(),
+            path: fpath.to_string(),
+            old_hash: old_hash.map(str::to_string),
+            new_hash: new_hash.map(str::to_string),
+            diff,
+            host: webhook::hostname(),
+            time: unix_now(),
+        },
+    )
+}
+
+fn select(d: &mut Daemon, peer: Option<&Peer>, pkt: &Packet) -> Result<Vec<u8>> {
+    let Select {
+        fpath,
+        version,
+        run_hooks,
+    } = pkt.decode::<Select>().map_err(invalid)?;
 
-    let nfpath = state
+    let entry = d
+        .state
         .files
         .get(&fpath)
-        .context("Found no such tracked file")?
-        .snapshots
-        .get(&hash)
-        .context("Found no such file version")?
-        .clone()
-        .1;
+        .ok_or_else(|| not_tracked(&fpath))?;
+    let hash = resolve(entry, &version)?;
+    let snapshot = snapshot_of(entry, &hash)?.clone();
 
-    std::fs::copy(&nfpath, &fpath).context("Failed to copy file {nfpath} to {fpath}")?;
-    Ok(format!("Selected {nfpath} ==> {fpath}").as_bytes().to_vec())
+    let old_hash = save_current(&mut d.state, &d.store, &fpath)?;
+    let res = restore(&d.store, &snapshot, Path::new(&fpath));
+    d.audit.record(AuditRecord {
+        old_hash,
+        new_hash: Some(hash.clone()),
+        error: res.as_ref().err().map(|e| format!("{:#}", e)),
+        uid: peer.map(|p| p.uid),
+        pid: peer.map(|p| p.pid),
+        ..AuditRecord::new(&fpath, AuditEvent::Select)
+    });
+    res?;
+    d.own_writes
+        .insert(fpath.clone(), (hash.clone(), Instant::now()));
+    restored(d, &fpath, &hash, &snapshot, run_hooks);
+    d.state.save()?;
+    respond_json(&FileVersion { path: fpath, hash })
 }
 
-fn track(state: &mut State, pkt: &Packet) -> Result<Vec<u8>> {
-    let track = bincode::deserialize::<Track>(&pkt.payload).context("Failed to deserialize")?;
+fn cat(d: &Daemon, pkt: &Packet) -> Result<Vec<u8>> {
+    let Cat { fpath, version } = pkt.decode::<Cat>().map_err(invalid)?;
+    let entry = d
+        .state
+        .files
+        .get(&fpath)
+        .ok_or_else(|| not_tracked(&fpath))?;
+    let hash = resolve(entry, &version)?;
+    d.store.read(snapshot_of(entry, &hash)?)
+}
 
-    save(state, &track.fpath, &track.alias)?;
-    state
+fn diff(d: &Daemon, pkt: &Packet) -> Result<Vec<u8>> {
+    let Diff { fpath, from, to } = pkt.decode::<Diff>().map_err(invalid)?;
+    let entry = d
+        .state
         .files
-        .entry(track.fpath.clone())
-        .and_modify(|x| x.action = track.action.clone());
-    Ok(format!(
-        "Added {} with action {:?} and alias method {:?} to tracked files",
-        &track.fpath, &track.action, &track.alias,
+        .get(&fpath)
+        .ok_or_else(|| not_tracked(&fpath))?;
+
+    let from = resolve(entry, &from)?;
+    let old = d.store.read(snapshot_of(entry, &from)?)?;
+    let (to, new) = match to {
+        Some(to) => {
+            let to = resolve(entry, &to)?;
+            let new = d.store.read(snapshot_of(entry, &to)?)?;
+            (to, new)
+        }
+        None => (
+            String::from("current"),
+            std::fs::read(&fpath).context(format!("Failed to read {fpath}"))?,
+        ),
+    };
+
+    let mut patch = git2::Patch::from_buffers(
+        &old,
+        Some(Path::new(&format!("{fpath} {from}"))),
+        &new,
+        Some(Path::new(&format!("{fpath} {to}"))),
+        None,
     )
-    .as_bytes()
-    .to_vec())
+    .context("Failed to diff versions")?;
+    let buf = patch.to_buf().context("Failed to format diff")?;
+    Ok(buf.to_vec())
 }
 
-fn process(socket: &mut UnixStream, state: &mut State) -> bool {
-    let mut buf: [u8; 1024] = [0; 1024];
-    let mut reload = false;
-    // This should read only as much data as we are
-    // expecting, perhaps via serialize_size?
-    if socket.read(&mut buf).is_ok() {
-        let pkt = bincode::deserialize::<Packet>(&buf).unwrap();
-        let res = match pkt.command {
-            Command::Echoerr => echoerr(&pkt),
-            Command::Echo => echo(&pkt),
-            Command::List => list(state, &pkt),
-            Command::Select => {
-                reload = true;
-                select(state, &pkt)
+fn tag(state: &mut State, pkt: &Packet) -> Result<Vec<u8>> {
+    let Tag {
+        fpath,
+        version,
+        add,
+        remove,
+        note,
+    } = pkt.decode::<Tag>().map_err(invalid)?;
+    let entry = state
+        .files
+        .get_mut(&fpath)
+        .ok_or_else(|| not_tracked(&fpath))?;
+    let hash = resolve(entry, &version)?;
+    let snapshot = entry
+        .snapshots
+        .get_mut(&hash)
+        .context("Found no such file version")?;
+
+    snapshot.labels.retain(|l| !remove.contains(l));
+    for label in add {
+        if !snapshot.labels.contains(&label) {
+            snapshot.labels.push(label);
+        }
+    }
+    if let Some(note) = note {
+        snapshot.note = Some(note).filter(|n| !n.is_empty());
+    }
+    let item = list_item(&fpath, &hash, snapshot);
+    state.save()?;
+    respond_json(&item)
+}
+
+fn pin(state: &mut State, pkt: &Packet) -> Result<Vec<u8>> {
+    let Pin {
+        fpath,
+        version,
+        pinned,
+    } = pkt.decode::<Pin>().map_err(invalid)?;
+    let entry = state
+        .files
+        .get_mut(&fpath)
+        .ok_or_else(|| not_tracked(&fpath))?;
+    let hash = resolve(entry, &version)?;
+    let snapshot = entry
+        .snapshots
+        .get_mut(&hash)
+        .context("Found no such file version")?;
+    snapshot.pinned = pinned;
+    let item = list_item(&fpath, &hash, snapshot);
+    state.save()?;
+    respond_json(&item)
+}
+
+/// Remove the oldest snapshots of a file until at most `keep` unpinned
+/// snapshots are left. The current version and versions which are part of
+/// a checkpoint are never removed.
+fn prune(
+    store: &Store,
+    fname: &str,
+    entry: &mut Entry,
+    keep: usize,
+    checkpointed: &[String],
+) -> usize {
+    let unpinned = entry.snapshots.values().filter(|s| !s.pinned).count();
+    let versions = versions(&entry.history);
+    let mut candidates: Vec<String> = entry
+        .snapshots
+        .iter()
+        .filter(|(hash, s)| {
+            !s.pinned && Some(*hash) != entry.hash.as_ref() && !checkpointed.contains(hash)
+        })
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    // Snapshots from before the history was recorded are the oldest
+    candidates.sort_by_key(|hash| versions.iter().position(|v| v == hash).map(|i| i + 1));
+    candidates.truncate(unpinned.saturating_sub(keep));
+
+    for hash in &candidates {
+        if let Some(s) = entry.snapshots.remove(hash) {
+            info!("Pruning snapshot {} of {}", hash, fname);
+            store.remove(&s);
+        }
+    }
+    candidates.len()
+}
+
+fn history(state: &State, pkt: &Packet) -> Result<Vec<u8>> {
+    let fname = pkt.decode::<String>().map_err(invalid)?;
+    let entry = state.files.get(&fname).ok_or_else(|| not_tracked(&fname))?;
+
+    let mut resp = vec![];
+    for rev in &entry.history {
+        resp.append(&mut serde_json::to_vec(rev)?);
+        resp.push(b'\n');
+    }
+    Ok(resp)
+}
+
+fn track(d: &mut Daemon, peer: Option<&Peer>, pkt: &Packet) -> Result<Vec<u8>> {
+    let track = pkt.decode::<Track>().map_err(invalid)?;
+
+    let old_hash = d.state.files.get(&track.fpath).and_then(|e| e.hash.clone());
+    let (hash, alias) = save(
+        &mut d.state,
+        &d.store,
+        &track.fpath,
+        &track.alias,
+        None,
+        "track",
+    )?;
+    d.subs.publish(
+        &track.fpath,
+        EventKind::Saved {
+            hash: hash.clone(),
+            alias,
+        },
+    );
+    d.state.files.entry(track.fpath.clone()).and_modify(|x| {
+        x.action = track.action.clone();
+        x.hash = Some(hash.clone());
+    });
+    d.audit.record(AuditRecord {
+        old_hash,
+        new_hash: Some(hash.clone()),
+        action: Some(track.action.clone()),
+        uid: peer.map(|p| p.uid),
+        pid: peer.map(|p| p.pid),
+        ..AuditRecord::new(&track.fpath, AuditEvent::Track)
+    });
+    respond_json(&FileVersion {
+        path: track.fpath,
+        hash,
+    })
+}
+
+/// Snapshot tracked files in one go, requests are handled one at a time so
+/// no change is processed in between. Returns path --> hash.
+fn snapshot_files(
+    d: &mut Daemon,
+    peer: Option<&Peer>,
+    mut fnames: Vec<String>,
+    message: &Option<String>,
+) -> Result<BTreeMap<String, String>> {
+    if let Some(fname) = fnames.iter().find(|f| !d.state.files.contains_key(*f)) {
+        return Err(not_tracked(fname));
+    }
+    fnames.sort();
+    fnames.dedup();
+    // Snapshots are taken together or not at all, a file which can not be
+    // read fails the request before any is taken
+    for fname in &fnames {
+        std::fs::File::open(fname).context(format!("Failed to snapshot {fname}"))?;
+    }
+
+    let mut files = BTreeMap::new();
+    for fname in fnames {
+        let entry = d
+            .state
+            .files
+            .get(&fname)
+            .ok_or_else(|| not_tracked(&fname))?;
+        let old_hash = entry.hash.clone();
+        let alias = entry.alias.clone();
+        let (hash, alias) = save(&mut d.state, &d.store, &fname, &alias, None, "snapshot")
+            .context(format!("Failed to snapshot {fname}"))?;
+        if let Some(entry) = d.state.files.get_mut(&fname) {
+            entry.hash = Some(hash.clone());
+            if let (Some(msg), Some(snapshot)) = (message, entry.snapshots.get_mut(&hash)) {
+                snapshot.note = Some(msg.clone());
             }
-            Command::Track => {
-                reload = true;
-                track(state, &pkt)
+        }
+        d.subs.publish(
+            &fname,
+            EventKind::Saved {
+                hash: hash.clone(),
+                alias,
+            },
+        );
+        d.audit.record(AuditRecord {
+            old_hash,
+            new_hash: Some(hash.clone()),
+            uid: peer.map(|p| p.uid),
+            pid: peer.map(|p| p.pid),
+            ..AuditRecord::new(&fname, AuditEvent::Snapshot)
+        });
+        files.insert(fname, hash);
+    }
+    Ok(files)
+}
+
+fn snapshot(d: &mut Daemon, peer: Option<&Peer>, pkt: &Packet) -> Result<Vec<u8>> {
+    let ForceSnapshot { fpath, message } = pkt.decode::<ForceSnapshot>().map_err(invalid)?;
+
+    let fnames = match &fpath {
+        Some(fpath) => vec![fpath.clone()],
+        None => d.state.files.keys().cloned().collect(),
+    };
+    let time = unix_now();
+    let files = snapshot_files(d, peer, fnames, &message)?;
+
+    let mut checkpoint = None;
+    if fpath.is_none() {
+        // Unique, the same second may already have a checkpoint
+        let mut name = format!("checkpoint-{time}");
+        let mut n = 1;
+        while d.state.checkpoints.contains_key(&name) {
+            n += 1;
+            name = format!("checkpoint-{time}-{n}");
+        }
+        d.state.checkpoints.insert(
+            name.clone(),
+            Checkpoint {
+                time,
+                message,
+                files: files.clone(),
+            },
+        );
+        checkpoint = Some(name);
+    }
+    d.state.save()?;
+    respond_json(&Taken { checkpoint, files })
+}
+
+fn checkpoint(d: &mut Daemon, peer: Option<&Peer>, pkt: &Packet) -> Result<Vec<u8>> {
+    let NewCheckpoint {
+        name,
+        files,
+        message,
+    } = pkt.decode::<NewCheckpoint>().map_err(invalid)?;
+    if d.state.checkpoints.contains_key(&name) {
+        return Err(anyhow!("Checkpoint {name} already exists"));
+    }
+
+    let fnames = if files.is_empty() {
+        d.state.files.keys().cloned().collect()
+    } else {
+        files
+    };
+    let time = unix_now();
+    let files = snapshot_files(d, peer, fnames, &message)?;
+    d.state.checkpoints.insert(
+        name.clone(),
+        Checkpoint {
+            time,
+            message,
+            files: files.clone(),
+        },
+    );
+    d.state.save()?;
+    respond_json(&Taken {
+        checkpoint: Some(name),
+        files,
+    })
+}
+
+fn checkpoints(state: &State) -> Result<Vec<u8>> {
+    serde_json::to_vec(&state.checkpoints).context("Failed to serialize checkpoints")
+}
+
+/// Put back the content which was replaced by restoring a checkpoint,
+/// `None` if the file did not exist. Returns the files which could not be
+/// put back.
+fn rollback(d: &mut Daemon, files: &[(String, Option<String>)]) -> Vec<String> {
+    let mut failed = vec![];
+    for (fpath, old_hash) in files {
+        let res = match old_hash {
+            Some(old_hash) => {
+                let snapshot = d
+                    .state
+                    .files
+                    .get(fpath)
+                    .and_then(|e| e.snapshots.get(old_hash))
+                    .cloned();
+                d.own_writes
+                    .insert(fpath.clone(), (old_hash.clone(), Instant::now()));
+                snapshot
+                    .context("Found no snapshot of the replaced content")
+                    .and_then(|s| restore(&d.store, &s, Path::new(fpath)))
             }
+            None => std::fs::remove_file(fpath).context("Failed to remove restored file"),
         };
+        if let Err(e) = res {
+            error!("Failed to roll back {}, {:#}", fpath, e);
+            failed.push(fpath.clone());
+        }
+    }
+    failed
+}
 
-        match res {
-            Ok(resp) => {
-                debug!("Responding to request {:#?}", pkt.command);
-                socket
-                    .write_all(&resp)
-                    .context("Failed to write to socket")
-                    .unwrap()
-            }
-            Err(msg) => {
-                let msg = format!("Failed to process request {:#?}, {:?}", pkt.command, msg);
-                error!("{}", msg);
-                socket
-                    .write_all(msg.as_bytes())
-                    .context("Failed to write to socket")
-                    .unwrap();
+/// Restore every file of a checkpoint, or none of them. Files restored
+/// before one fails are put back as they were.
+fn restore_checkpoint(d: &mut Daemon, peer: Option<&Peer>, pkt: &Packet) -> Result<Vec<u8>> {
+    let RestoreCheckpoint { name, run_hooks } =
+        pkt.decode::<RestoreCheckpoint>().map_err(invalid)?;
+    let checkpoint = d
+        .state
+        .checkpoints
+        .get(&name)
+        .context("Found no such checkpoint")?
+        .clone();
+
+    // Everything is looked up before any file is touched
+    let mut targets = vec![];
+    for (fpath, hash) in &checkpoint.files {
+        let snapshot = d
+            .state
+            .files
+            .get(fpath)
+            .context(format!("{fpath} is no longer tracked"))?
+            .snapshots
+            .get(hash)
+            .context(format!("Found no snapshot {hash} of {fpath}"))?
+            .clone();
+        targets.push((fpath.clone(), hash.clone(), snapshot));
+    }
+    let mut olds = vec![];
+    for (fpath, _, _) in &targets {
+        let old_hash = save_current(&mut d.state, &d.store, fpath)?;
+        olds.push((fpath.clone(), old_hash));
+    }
+
+    for (i, (fpath, hash, snapshot)) in targets.iter().enumerate() {
+        d.own_writes
+            .insert(fpath.clone(), (hash.clone(), Instant::now()));
+        if let Err(e) = restore(&d.store, snapshot, Path::new(fpath)) {
+            d.audit.record(AuditRecord {
+                old_hash: olds[i].1.clone(),
+                new_hash: Some(hash.clone()),
+                error: Some(format!("{:#}", e)),
+                uid: peer.map(|p| p.uid),
+                pid: peer.map(|p| p.pid),
+                ..AuditRecord::new(fpath, AuditEvent::Select)
+            });
+            let failed = rollback(d, &olds[..i]);
+            let rolled_back = if failed.is_empty() {
+                String::from("rolled back all files")
+            } else {
+                format!("failed to roll back {}", failed.join(", "))
+            };
+            return Err(e.context(format!(
+                "Failed to restore {fpath} of checkpoint {name}, {rolled_back}"
+            )));
+        }
+    }
+
+    for ((fpath, hash, snapshot), (_, old_hash)) in targets.iter().zip(olds) {
+        d.audit.record(AuditRecord {
+            old_hash,
+            new_hash: Some(hash.clone()),
+            uid: peer.map(|p| p.uid),
+            pid: peer.map(|p| p.pid),
+            ..AuditRecord::new(fpath, AuditEvent::Select)
+        });
+        restored(d, fpath, hash, snapshot, run_hooks);
+    }
+    d.state.save()?;
+    let restored: Vec<FileVersion> = targets
+        .into_iter()
+        .map(|(path, hash, _)| FileVersion { path, hash })
+        .collect();
+    respond_json(&restored)
+}
+
+fn export(d: &Daemon, pkt: &Packet) -> Result<Vec<u8>> {
+    let state = &d.state;
+    let Export { files, compress } = pkt.decode::<Export>().map_err(invalid)?;
+
+    let entries = if files.is_empty() {
+        state.files.clone()
+    } else {
+        let mut entries = HashMap::new();
+        for fpath in files {
+            let entry = state
+                .files
+                .get(&fpath)
+                .ok_or_else(|| not_tracked(&fpath))?
+                .clone();
+            entries.insert(fpath, entry);
+        }
+        entries
+    };
+    archive::export(entries, compress, |s| d.store.read(s))
+}
+
+/// Add the snapshots and history of an imported entry to an existing one.
+fn merge(into: &mut Entry, from: Entry) {
+    for (hash, snapshot) in from.snapshots {
+        into.snapshots.entry(hash).or_insert(snapshot);
+    }
+    for rev in from.history {
+        if !into
+            .history
+            .iter()
+            .any(|r| r.time == rev.time && r.hash == rev.hash && r.kind == rev.kind)
+        {
+            into.history.push(rev);
+        }
+    }
+    into.history.sort_by_key(|r| r.time);
+}
+
+fn import(d: &mut Daemon, pkt: &Packet) -> Result<Vec<u8>> {
+    let Import { path } = pkt.decode::<Import>().map_err(invalid)?;
+    let files = archive::import(Path::new(&path), &d.indexd)?;
+
+    // path --> snapshots imported
+    let mut imported = BTreeMap::new();
+    for (fpath, mut entry) in files {
+        imported.insert(fpath.clone(), entry.snapshots.len());
+        match d.state.files.get_mut(&fpath) {
+            Some(existing) => merge(existing, entry),
+            None => {
+                // Seen on another host, not by this daemon
+                entry.hash = None;
+                d.state.files.insert(fpath, entry);
             }
-        };
+        }
     }
-    reload
+    d.state.save()?;
+    respond_json(&imported)
 }
 
-fn listen(listener: &UnixListener, state: &mut State) -> bool {
-    match listener.accept() {
-        Ok((mut s, _)) => {
-            info!(
-                "Processing request on {:#?} from {:#?}",
-                s.local_addr().unwrap(),
-                s.peer_addr().unwrap()
-            );
-            process(&mut s, state)
+fn subscribe(socket: &UnixStream, subs: &mut Subscribers, pkt: &Packet) -> Result<()> {
+    let filters = pkt.decode::<Vec<String>>().map_err(invalid)?;
+    let stream = socket
+        .try_clone()
+        .context("Failed to clone subscriber socket")?;
+
+    bincode::serialize_into(&stream, &Response::Ok(vec![]))
+        .context("Failed to respond to subscriber")?;
+    info!("Adding subscriber with filters {:?}", filters);
+    subs.add(stream, filters);
+    Ok(())
+}
+
+fn audit(d: &Daemon, pkt: &Packet) -> Result<Vec<u8>> {
+    let query = pkt.decode::<AuditQuery>().map_err(invalid)?;
+
+    let mut resp = vec![];
+    for rec in d.audit.query(&query)? {
+        resp.append(&mut serde_json::to_vec(&rec)?);
+        resp.push(b'\n');
+    }
+    Ok(resp)
+}
+
+fn load_policy(args: &Args) -> Option<Policy> {
+    match args.policy.as_ref().map(|p| Policy::load(p)) {
+        Some(Ok(policy)) => Some(policy),
+        Some(Err(e)) => {
+            // Fail closed rather than silently allowing everything
+            error!("{:#}, denying all requests", e);
+            Some(Policy::default())
         }
-        Err(msg) => {
-            error!("{}", msg);
-            false
+        None => None,
+    }
+}
+
+fn process(server: &Server, req: Request, d: &mut Daemon) -> bool {
+    let Request { stream, pkt, peer } = req;
+    let mut reload = false;
+    d.metrics.request(&pkt.command);
+
+    if let Some(policy) = &d.policy {
+        if let Err(reason) = policy.check(peer.as_ref(), &pkt) {
+            let who = peer.map_or_else(|| String::from("unknown peer"), |p| p.to_string());
+            warn!("Denied request {:?} from {}, {}", pkt.command, who, reason);
+            let msg = format!("Permission denied for {:?}, {}", pkt.command, reason);
+            server.respond(stream, Response::Err(ErrorKind::PermissionDenied, msg));
+            return reload;
         }
     }
+
+    let res = match pkt.command {
+        Command::Subscribe => match subscribe(&stream, &mut d.subs, &pkt) {
+            // The subscriber keeps the connection for the events
+            Ok(_) => return reload,
+            Err(e) => Err(e),
+        },
+        Command::Echoerr => echoerr(&pkt),
+        Command::Echo => echo(&pkt),
+        Command::Status => status(d),
+        Command::List => list(&d.state, &pkt),
+        Command::Audit => audit(d, &pkt),
+        Command::History => history(&d.state, &pkt),
+        Command::Cat => cat(d, &pkt),
+        Command::Diff => diff(d, &pkt),
+        Command::Tag => tag(&mut d.state, &pkt),
+        Command::Pin => pin(&mut d.state, &pkt),
+        Command::Snapshot => snapshot(d, peer.as_ref(), &pkt),
+        Command::Checkpoint => checkpoint(d, peer.as_ref(), &pkt),
+        Command::Checkpoints => checkpoints(&d.state),
+        Command::RestoreCheckpoint => {
+            reload = true;
+            restore_checkpoint(d, peer.as_ref(), &pkt)
+        }
+        Command::Export => export(d, &pkt),
+        Command::Import => {
+            reload = true;
+            import(d, &pkt)
+        }
+        Command::Select => {
+            reload = true;
+            select(d, peer.as_ref(), &pkt)
+        }
+        Command::Track => {
+            reload = true;
+            track(d, peer.as_ref(), &pkt)
+        }
+    };
+
+    match res {
+        Ok(resp) => {
+            debug!("Responding to request {:#?}", pkt.command);
+            server.respond(stream, Response::Ok(resp));
+        }
+        Err(e) => {
+            let kind = e
+                .chain()
+                .find_map(|c| c.downcast_ref::<Failure>())
+                .map_or(ErrorKind::Failed, |f| f.kind);
+            let msg = format!("{:#}", e);
+            error!("Failed to process request {:?}, {}", pkt.command, msg);
+            server.respond(stream, Response::Err(kind, msg));
+        }
+    };
+    reload
+}
+
+fn listen(server: &Server, d: &mut Daemon) -> bool {
+    let mut reload = false;
+    for req in server.requests() {
+        reload |= process(server, req, d);
+    }
+    reload
+}
+
+/// Record a change of ownership, permissions or extended attributes.
+/// Content is not saved, the snapshots keep the metadata they were taken with.
+fn metadata_change(d: &mut Daemon, fname: &str) -> Result<()> {
+    let current = metadata::capture(Path::new(fname))?;
+    let entry = d
+        .state
+        .files
+        .get_mut(fname)
+        .ok_or_else(|| not_tracked(fname))?;
+
+    // Timestamps alone also cause IN_ATTRIB
+    if entry.metadata.as_ref() == Some(&current) {
+        return Ok(());
+    }
+
+    info!("Metadata of {:?} changed", fname);
+    entry.metadata = Some(current.clone());
+    let hash = entry.hash.clone();
+    d.subs.publish(fname, EventKind::MetadataChanged);
+    d.audit.record(AuditRecord {
+        old_hash: hash.clone(),
+        new_hash: hash,
+        metadata: Some(current),
+        ..AuditRecord::new(fname, AuditEvent::Metadata)
+    });
+    d.state.save()?;
+    Ok(())
 }
 
-fn action(state: &mut State, fname: &str) -> Result<()> {
-    let entry = &state.files[fname].clone();
+/// Whether the change was caused by the daemon writing the file itself, such
+/// as when restoring it.
+fn own_write(d: &mut Daemon, c: &Change) -> bool {
+    if let Some(m) = &c.modifier {
+        if m.pid == std::process::id() as i32 {
+            return true;
+        }
+    }
+
+    d.own_writes
+        .retain(|_, (_, at)| at.elapsed() < OWN_WRITE_WINDOW);
+    match d.own_writes.get(&c.path) {
+        Some((hash, _)) => sha256sum(Path::new(&c.path)).ok().as_ref() == Some(hash),
+        None => false,
+    }
+}
+
+fn action(d: &mut Daemon, fname: &str, modifier: Option<Modifier>) -> Result<()> {
+    // The index may have been reloaded without the file since it was watched
+    let entry = &d
+        .state
+        .files
+        .get(fname)
+        .ok_or_else(|| not_tracked(fname))?
+        .clone();
+    let new_hash = sha256sum(Path::new(fname)).ok();
 
     info!("Action {:?} on {:?}", &entry.action, &fname);
-    match &entry.action {
-        Action::Save => save(state, fname, &entry.alias),
-        Action::Script(spath) => script(fname, spath),
+    let res = match &entry.action {
+        Action::Save => save(
+            &mut d.state,
+            &d.store,
+            fname,
+            &entry.alias,
+            modifier,
+            "change",
+        )
+        .map(|(hash, alias)| d.subs.publish(fname, EventKind::Saved { hash, alias })),
+        Action::Script(spath) => script(fname, spath, "change"),
+        Action::Webhook(url) => save(
+            &mut d.state,
+            &d.store,
+            fname,
+            &entry.alias,
+            modifier,
+            "change",
+        )
+        .map(|(hash, alias)| d.subs.publish(fname, EventKind::Saved { hash, alias }))
+        .and_then(|_| {
+            webhook(
+                d,
+                fname,
+                url,
+                "change",
+                entry.hash.as_deref(),
+                new_hash.as_deref(),
+            )
+        }),
+    };
+
+    d.metrics.action(&entry.action, res.is_ok());
+    match &res {
+        Ok(_) => d
+            .subs
+            .publish(fname, EventKind::ActionRun(entry.action.clone())),
+        Err(e) => d.subs.publish(
+            fname,
+            EventKind::ActionFailed {
+                action: entry.action.clone(),
+                error: format!("{:#}", e),
+            },
+        ),
+    };
+
+    let checkpointed: Vec<String> = d
+        .state
+        .checkpoints
+        .values()
+        .filter_map(|c| c.files.get(fname).cloned())
+        .collect();
+    if let Some(e) = d.state.files.get_mut(fname) {
+        e.hash = new_hash.clone();
+        if d.max_snapshots > 0 && prune(&d.store, fname, e, d.max_snapshots, &checkpointed) > 0 {
+            if let Err(e) = d.state.save() {
+                error!("{:#}", e);
+            }
+        }
     }
+    d.audit.record(AuditRecord {
+        old_hash: entry.hash.clone(),
+        new_hash,
+        action: Some(entry.action.clone()),
+        error: res.as_ref().err().map(|e| format!("{:#}", e)),
+        ..AuditRecord::new(fname, AuditEvent::Change)
+    });
+    res
 }
 
-fn main() {
-    let args = Args::parse();
-    let _ = unlink(SOCK_PATH);
-    let listener = UnixListener::bind(SOCK_PATH).unwrap();
+fn publish_metrics(d: &mut Daemon, watcher: &Watcher) {
+    let gauges = metrics::Gauges {
+        files: d.state.files.len(),
+        snapshots: d.state.files.values().map(|e| e.snapshots.len()).sum(),
+        watched: watcher.watched(),
+    };
+    d.metrics.publish(&gauges, &d.store);
+}
 
-    let wdir = args.working_directory.clone();
+/// Descriptors the event loop waits on: new clients, file changes and
+/// wakeups. Only readability is asked for, the wake pipe is always
+/// writable and would have the loop spin.
+fn poll_fds(server: &Server, watcher: &Watcher) -> Vec<PollFd> {
+    [server.listen_fd(), watcher.as_raw_fd(), server.wake_fd()]
+        .iter()
+        .map(|x| PollFd::new(*x, PollFlags::POLLIN))
+        .collect()
+}
+
+/// Bind the control socket, replacing a socket left behind by an instance
+/// which is gone but not one which is still listening.
+fn bind(path: &str) -> Result<UnixListener> {
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            return Err(anyhow!(
+                "Another fwatchd is listening on {path}, use --socket to run another instance"
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            // Connecting to any other file is refused as well
+            let meta = std::fs::symlink_metadata(path).context(format!("Failed to stat {path}"))?;
+            if !meta.file_type().is_socket() {
+                return Err(anyhow!("{path} is not a socket, refusing to remove it"));
+            }
+            unlink(path).context(format!("Failed to remove stale socket {path}"))?;
+        }
+        Err(_) => {}
+    }
+    UnixListener::bind(path).context(format!("Failed to bind {path}"))
+}
+
+/// Socket and working directory of the instance, which default to the
+/// runtime and data directories of the user with --per-user.
+fn instance_paths(args: &Args) -> Result<(String, String)> {
+    let socket = match (&args.socket, args.per_user) {
+        (Some(socket), _) => socket.clone(),
+        (None, false) => SOCK_PATH.to_string(),
+        (None, true) => user_socket()
+            .ok_or_else(|| anyhow!("XDG_RUNTIME_DIR is not set, use --socket"))?
+            .display()
+            .to_string(),
+    };
+    let wdir = match (&args.working_directory, args.per_user) {
+        (Some(wdir), _) => wdir.clone(),
+        (None, false) => String::from("/var/run/fwatch"),
+        (None, true) => dirs::data_dir()
+            .ok_or_else(|| anyhow!("Found no data directory, use --working-directory"))?
+            .join("fwatch")
+            .display()
+            .to_string(),
+    };
+    Ok((socket, wdir))
+}
+
+fn run(args: Args) -> Result<()> {
+    if let Some(dir) = &args.man {
+        std::fs::create_dir_all(dir).context(format!("Failed to create {dir}"))?;
+        return clap_mangen::generate_to(Args::command(), dir)
+            .context(format!("Failed to write man page to {dir}"));
+    }
+    let (socket, wdir) = instance_paths(&args)?;
+    // A socket passed by socket activation is already set up
+    let (listener, activated) = match systemd::listener() {
+        Ok(Some(listener)) => (Ok(listener), true),
+        Ok(None) => (bind(&socket), false),
+        Err(e) => (Err(e), true),
+    };
+    let listener = listener?;
+    let fanotify = args.fanotify.then(Watcher::fanotify);
+    // Read while still privileged, the secret is best kept from the daemon user
+    let webhook_secret = match &args.webhook_secret_file {
+        Some(f) => Some(std::fs::read(f).context(format!("Failed to read webhook secret {f}"))?),
+        None => None,
+    }
+    .map(|mut s| {
+        s.truncate(s.trim_ascii_end().len());
+        s
+    });
+
+    std::fs::create_dir_all(&wdir).context("Failed to create runtime directory")?;
     let ddir = PathBuf::from(&wdir);
-    unsafe {
-        let uname = CString::new(args.user.clone())
-            .context("Found no such user")
-            .unwrap();
+    let daemonize = if args.foreground {
+        // Run as whoever started the daemon, e.g. systemd with User=
+        None
+    } else if args.per_user {
+        // Already running as the user whose files are tracked
+        Some(
+            Daemonize::new()
+                .working_directory(&ddir)
+                .privileged_action(|| Ok(())),
+        )
+    } else {
+        let uname = CString::new(args.user.clone()).context("Found no such user")?;
 
-        let pw = libc::getpwnam(uname.as_ptr());
+        let pw = unsafe { libc::getpwnam(uname.as_ptr()) };
         if pw.is_null() {
-            println!("No such user {}", args.user);
-            return;
-        }
-        let uid: u32 = (*pw).pw_uid;
-        let gid: u32 = (*pw).pw_gid;
-
-        std::fs::create_dir_all(args.working_directory.clone())
-            .context("Failed to create runtime directory")
-            .unwrap();
-
-        let daemonize = Daemonize::new()
-            .chown_pid_file(true)
-            .working_directory(&ddir)
-            .user(uid)
-            .group(gid)
-            .privileged_action(move || {
-                chown(&ddir, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
-                    .context("Failed to change owner/group of working directory")
-                    .unwrap();
-                chown(&PathBuf::from(SOCK_PATH), None, Some(Gid::from_raw(gid)))
-                    .context("Failed to change owner/group of socket")
-                    .unwrap();
-            });
-
-        if !args.foreground {
-            match daemonize.start() {
-                Ok(_) => {}
-                Err(e) => println!("Failed to daemonize: {}", e),
-            }
+            return Err(anyhow!("No such user {}", args.user));
         }
+        let (uid, gid) = unsafe { ((*pw).pw_uid, (*pw).pw_gid) };
+        let socket = socket.clone();
+        Some(
+            Daemonize::new()
+                .chown_pid_file(true)
+                .working_directory(&ddir)
+                .user(uid)
+                .group(gid)
+                .privileged_action(move || -> Result<()> {
+                    chown(&ddir, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+                        .context("Failed to change owner/group of working directory")?;
+                    if !activated {
+                        chown(&PathBuf::from(&socket), None, Some(Gid::from_raw(gid)))
+                            .context("Failed to change owner/group of socket")?;
+                    }
+                    Ok(())
+                }),
+        )
     };
 
-    std::fs::create_dir_all(Path::new(&args.working_directory).join("index.d"))
-        .context("Failed to create runtime directory")
-        .unwrap();
+    if let Some(daemonize) = daemonize {
+        daemonize
+            .start()
+            .map_err(|e| anyhow!("Failed to daemonize: {}", e))??;
+    }
+
+    std::fs::create_dir_all(index_dir(&wdir)).context("Failed to create runtime directory")?;
 
     if args.foreground {
         log::set_logger(&StdoutLog {
             level: Level::Debug,
         })
-        .expect("Failed to setup logger");
+        .context("Failed to setup logger")?;
         log::set_max_level(LevelFilter::Debug);
     } else {
         let formatter = Formatter3164 {
@@ -408,105 +1550,404 @@ fn main() {
             process: "fwatch".into(),
             pid: 0,
         };
-        let logger = syslog::unix(formatter).expect("Failed to open syslog");
+        let logger =
+            syslog::unix(formatter).map_err(|e| anyhow!("Failed to open syslog, {}", e))?;
 
         log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
             .map(|()| log::set_max_level(LevelFilter::Debug))
-            .expect("Failed to setup logger");
+            .context("Failed to setup logger")?;
     };
 
-    let mut state = load_index(&args.working_directory);
-    let mut wdm = HashMap::new();
-    let mut buffer = [0; 1024];
-    let mut inotify = Inotify::init().expect("Failed to intialize inotify object");
-    for (k, _) in state.files.clone() {
-        if let Ok(wd) = inotify.watches().add(&k, WatchMask::CLOSE_WRITE) {
-            wdm.insert(wd, k);
-        } else {
-            error!("Failed to add watch for {k}");
+    let audit_log = args
+        .audit_log
+        .clone()
+        .map_or_else(|| Path::new(&wdir).join("audit.log"), PathBuf::from);
+    let mut d = Daemon {
+        state: load_index(&wdir),
+        policy: load_policy(&args),
+        subs: Subscribers::default(),
+        audit: AuditLog::new(&audit_log, args.audit_max_size, args.audit_keep),
+        own_writes: HashMap::new(),
+        max_snapshots: args.max_snapshots,
+        started: unix_now(),
+        indexd: index_dir(&wdir),
+        metrics: Metrics::new(args.metrics_listen.as_deref(), args.metrics_file.as_deref())?,
+        outbox: Outbox::new(
+            &Path::new(&wdir).join("outbox"),
+            webhook_secret,
+            args.webhook_retries,
+        )?,
+        store: match &args.git {
+            Some(repo) => Store::git(Path::new(repo))?,
+            None => Store::files(&index_dir(&wdir)),
+        },
+    };
+    let mut watcher = match fanotify {
+        Some(Ok(w)) => w,
+        Some(Err(e)) => {
+            warn!("{:#}, falling back to inotify", e);
+            Watcher::inotify(args.persistent)?
+        }
+        None => Watcher::inotify(args.persistent)?,
+    };
+    for (k, _) in d.state.files.clone() {
+        if let Err(e) = watcher.add(&k) {
+            error!("{:#}", e);
         }
     }
 
-    let mut rfd: Vec<PollFd> = [listener.as_raw_fd(), inotify.as_raw_fd()]
-        .iter()
-        .map(|x| PollFd::new(*x, PollFlags::all()))
-        .collect();
+    let server = Server::new(listener, Duration::from_secs(args.client_timeout))
+        .context("Failed to setup control socket")?;
+    let mut rfd = poll_fds(&server, &watcher);
 
     let term = Arc::new(AtomicBool::new(false));
     let hup = Arc::new(AtomicBool::new(false));
 
-    flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term)).unwrap();
-    flag::register(signal_hook::consts::SIGINT, Arc::clone(&term)).unwrap();
-    flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hup)).unwrap();
+    for (signal, flag) in [
+        (signal_hook::consts::SIGTERM, &term),
+        (signal_hook::consts::SIGINT, &term),
+        (signal_hook::consts::SIGHUP, &hup),
+    ] {
+        flag::register(signal, Arc::clone(flag)).context("Failed to setup signal handler")?;
+        let waker = server.waker().context("Failed to setup signal handler")?;
+        pipe::register(signal, waker).context("Failed to setup signal handler")?;
+    }
+
+    // Ping the watchdog twice per interval, to not miss it while busy
+    let watchdog = systemd::watchdog_interval().map(|i| i / 2);
+    let mut pinged = Instant::now();
+    systemd::notify(&format!(
+        "READY=1\nSTATUS=Watching {} files",
+        d.state.files.len()
+    ));
+    publish_metrics(&mut d, &watcher);
     loop {
         #[cfg(target_os = "linux")]
-        let _ = ppoll(rfd.as_mut_slice(), None, SigSet::empty());
+        let _ = ppoll(
+            rfd.as_mut_slice(),
+            watchdog.map(TimeSpec::from),
+            SigSet::empty(),
+        );
 
         #[cfg(target_os = "macos")]
-        let _ = poll(rfd.as_mut_slice(), 0).unwrap();
-
-        let mut reload = match rfd[0].revents() {
-            Some(ev) => {
-                if !ev.is_empty() {
-                    listen(&listener, &mut state)
-                } else {
-                    false
-                }
+        let _ = poll(
+            rfd.as_mut_slice(),
+            watchdog.map_or(-1, |w| w.as_millis() as i32),
+        );
+
+        if let Some(every) = watchdog {
+            if pinged.elapsed() >= every {
+                systemd::notify("WATCHDOG=1");
+                pinged = Instant::now();
+            }
+        }
+
+        if let Some(ev) = rfd[0].revents() {
+            if !ev.is_empty() {
+                server.accept();
             }
-            None => false,
+        }
+
+        let mut reload = match rfd[2].revents() {
+            Some(ev) if !ev.is_empty() => listen(&server, &mut d),
+            _ => false,
         };
 
         if term.load(Ordering::Relaxed) {
             info!("Received SIGTERM | SIGINT, exiting");
+            systemd::notify("STOPPING=1");
             break;
         }
 
-        if hup.load(Ordering::Relaxed) {
+        if hup.swap(false, Ordering::Relaxed) {
             info!("Received SIGHUP, reloading index");
+            systemd::notify("RELOADING=1");
             // XXX: Please observe that this discards the current
             // state. This is likely not desired during normal execution
-            state = load_index(&args.working_directory);
+            d.state = load_index(&wdir);
+            d.policy = load_policy(&args);
             reload = true;
         }
 
         if reload {
-            info!("Reloading inotify watches");
-            for (k, _) in state.files.clone() {
-                if let Ok(wd) = inotify.watches().add(&k, WatchMask::CLOSE_WRITE) {
-                    wdm.insert(wd, k);
-                } else {
-                    error!("Failed to add watch for {k}");
+            info!("Reloading watches");
+            for (k, _) in d.state.files.clone() {
+                if let Err(e) = watcher.add(&k) {
+                    error!("{:#}", e);
+                    d.subs.publish(&k, EventKind::WatchLost);
                 }
             }
 
-            rfd = [listener.as_raw_fd(), inotify.as_raw_fd()]
-                .iter()
-                .map(|x| PollFd::new(*x, PollFlags::POLLIN))
-                .collect();
+            rfd = poll_fds(&server, &watcher);
+            systemd::notify(&format!(
+                "READY=1\nSTATUS=Watching {} files",
+                d.state.files.len()
+            ));
         }
 
-        let events = inotify.read_events(&mut buffer);
-        if events.is_err() {
-            continue;
-        }
+        for c in watcher.read_events() {
+            d.metrics.event(match c.kind {
+                ChangeKind::Written => "written",
+                ChangeKind::Metadata => "metadata",
+                ChangeKind::Lost => "lost",
+            });
+            if c.kind != ChangeKind::Lost && own_write(&mut d, &c) {
+                debug!("Ignoring change of {} made by the daemon", c.path);
+                continue;
+            }
 
-        for e in events.context("Events error").unwrap() {
-            debug!("Processing inotify event {:?}", e);
-            let name = wdm[&e.wd].clone();
-            if args.persistent && e.mask == EventMask::IGNORED {
-                if let Ok(wd) = inotify.watches().add(&name, WatchMask::CLOSE_WRITE) {
-                    wdm.remove(&e.wd);
-                    wdm.insert(wd, name.clone());
-                } else {
-                    warn!("Failed to add watch for {}", name);
+            match c.kind {
+                ChangeKind::Lost => d.subs.publish(&c.path, EventKind::WatchLost),
+                ChangeKind::Written => d.subs.publish(&c.path, EventKind::Changed),
+                ChangeKind::Metadata => {
+                    if let Err(e) = metadata_change(&mut d, &c.path) {
+                        error!("{:#}", e);
+                    }
+                    continue;
                 }
-            }
+            };
 
-            match action(&mut state, &name) {
+            match action(&mut d, &c.path, c.modifier) {
                 Ok(_) => {}
                 Err(msg) => error!("{}", msg),
             };
         }
+        publish_metrics(&mut d, &watcher);
+    }
+    d.state.save()?;
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        // stderr is gone once daemonized
+        error!("{:#}", e);
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon(dir: &Path) -> Daemon {
+        let workdir = dir.to_str().unwrap();
+        Daemon {
+            state: State::new(&format!("{workdir}/index")),
+            policy: None,
+            subs: Subscribers::default(),
+            audit: AuditLog::new(&dir.join("audit.log"), 1 << 20, 1),
+            own_writes: HashMap::new(),
+            max_snapshots: 0,
+            store: Store::files(&index_dir(workdir)),
+            started: unix_now(),
+            indexd: index_dir(workdir),
+            metrics: Metrics::default(),
+            outbox: Outbox::new(&dir.join("outbox"), None, 0).unwrap(),
+        }
+    }
+
+    fn written(fpath: &str, pid: Option<i32>) -> Change {
+        Change {
+            path: fpath.to_string(),
+            kind: ChangeKind::Written,
+            modifier: pid.map(|pid| Modifier {
+                pid,
+                exe: None,
+                uid: None,
+            }),
+        }
+    }
+
+    /// An entry with a snapshot for each distinct hash of `history`, of
+    /// (hash, alias, time).
+    fn entry(history: &[(&str, &str, u64)]) -> Entry {
+        let mut entry = Entry {
+            snapshots: HashMap::new(),
+            action: Action::Save,
+            alias: Alias::Basename,
+            hash: history.last().map(|(hash, _, _)| hash.to_string()),
+            metadata: None,
+            history: vec![],
+        };
+        for (hash, alias, time) in history {
+            entry.snapshots.insert(
+                hash.to_string(),
+                Snapshot {
+                    alias: alias.to_string(),
+                    path: String::new(),
+                    commit: None,
+                    modifier: None,
+                    metadata: None,
+                    labels: vec![],
+                    note: None,
+                    pinned: false,
+                },
+            );
+            entry.history.push(Revision {
+                time: *time,
+                hash: hash.to_string(),
+                kind: RevisionKind::Saved,
+            });
+        }
+        entry
+    }
+
+    fn resolve_err(entry: &Entry, version: Version) -> String {
+        format!("{:#}", resolve(entry, &version).unwrap_err())
+    }
+
+    #[test]
+    fn resolves_hash_prefix_alias_and_label() {
+        let mut e = entry(&[
+            ("aa11", "file", 10),
+            ("ab22", "file", 20),
+            ("cc33", "other", 30),
+        ]);
+        for hash in ["aa11", "cc33"] {
+            let snapshot = e.snapshots.get_mut(hash).unwrap();
+            snapshot.labels.push(String::from("reviewed"));
+        }
+        let snapshot = e.snapshots.get_mut("ab22").unwrap();
+        snapshot.labels.push(String::from("stable"));
+        let hash = |s: &str| resolve(&e, &Version::Hash(s.to_string())).unwrap();
+
+        assert_eq!(hash("aa11"), "aa11");
+        assert_eq!(hash("ab"), "ab22");
+        assert_eq!(hash("other"), "cc33");
+        assert_eq!(hash("stable"), "ab22");
+
+        let msg = resolve_err(&e, Version::Hash(String::from("a")));
+        assert!(msg.contains("matches several versions"), "{}", msg);
+        assert!(
+            msg.contains("aa11 (file)") && msg.contains("ab22 (file)"),
+            "{}",
+            msg
+        );
+        let msg = resolve_err(&e, Version::Hash(String::from("file")));
+        assert!(msg.contains("matches several versions"), "{}", msg);
+        let msg = resolve_err(&e, Version::Hash(String::from("reviewed")));
+        assert!(msg.contains("aa11") && msg.contains("cc33"), "{}", msg);
+        let msg = resolve_err(&e, Version::Hash(String::from("nope")));
+        assert!(msg.contains("Found no such file version nope"), "{}", msg);
+    }
+
+    #[test]
+    fn resolves_number_previous_and_time() {
+        // Back to the first version, then a third
+        let e = entry(&[
+            ("aa", "f", 10),
+            ("bb", "f", 20),
+            ("aa", "f", 30),
+            ("cc", "f", 40),
+        ]);
+
+        assert_eq!(resolve(&e, &Version::Number(1)).unwrap(), "aa");
+        assert_eq!(resolve(&e, &Version::Number(3)).unwrap(), "cc");
+        let msg = resolve_err(&e, Version::Number(4));
+        assert!(msg.contains("there are 3 versions"), "{}", msg);
+        assert!(resolve(&e, &Version::Number(0)).is_err());
+
+        assert_eq!(resolve(&e, &Version::Prev).unwrap(), "aa");
+        assert_eq!(resolve(&e, &Version::At(25)).unwrap(), "bb");
+        assert_eq!(resolve(&e, &Version::At(35)).unwrap(), "aa");
+        assert_eq!(resolve(&e, &Version::At(40)).unwrap(), "cc");
+        let msg = resolve_err(&e, Version::At(5));
+        assert!(
+            msg.contains("Found no version current at that time"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn rollback_puts_back_replaced_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut d = daemon(dir.path());
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (a, b) = (path("a"), path("b"));
+
+        // The content of a before it was restored, and b which did not exist
+        std::fs::write(path("saved"), "a1\n").unwrap();
+        let hash = sha256sum(Path::new(&path("saved"))).unwrap();
+        let mut e = entry(&[(&hash, "a", 10)]);
+        e.snapshots.get_mut(&hash).unwrap().path = path("saved");
+        d.state.files.insert(a.clone(), e);
+        std::fs::write(&a, "a2\n").unwrap();
+        std::fs::write(&b, "b2\n").unwrap();
+
+        let failed = rollback(
+            &mut d,
+            &[(a.clone(), Some(hash.clone())), (b.clone(), None)],
+        );
+        assert!(failed.is_empty(), "{:?}", failed);
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a1\n");
+        assert!(!Path::new(&b).exists());
+        assert_eq!(d.own_writes.get(&a).map(|(h, _)| h), Some(&hash));
+    }
+
+    #[test]
+    fn rollback_reports_files_it_can_not_put_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut d = daemon(dir.path());
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (a, b) = (path("a"), path("b"));
+        std::fs::write(&a, "a2\n").unwrap();
+
+        // a has no snapshot to put back, and b is already gone
+        let failed = rollback(
+            &mut d,
+            &[(a.clone(), Some(String::from("aa"))), (b.clone(), None)],
+        );
+        assert_eq!(failed, [a.clone(), b]);
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a2\n");
+    }
+
+    #[test]
+    fn own_writes_are_recognised_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut d = daemon(dir.path());
+        let file = dir.path().join("file");
+        let fpath = file.to_str().unwrap();
+        std::fs::write(&file, "restored\n").unwrap();
+        assert!(!own_write(&mut d, &written(fpath, None)));
+
+        let hash = sha256sum(&file).unwrap();
+        d.own_writes
+            .insert(fpath.to_string(), (hash, Instant::now()));
+        assert!(own_write(&mut d, &written(fpath, None)));
+
+        // Written again by someone else before the event was seen
+        std::fs::write(&file, "changed\n").unwrap();
+        assert!(!own_write(&mut d, &written(fpath, None)));
+    }
+
+    #[test]
+    fn own_writes_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut d = daemon(dir.path());
+        let file = dir.path().join("file");
+        let fpath = file.to_str().unwrap();
+        std::fs::write(&file, "restored\n").unwrap();
+
+        let long_ago = Instant::now() - OWN_WRITE_WINDOW;
+        let hash = sha256sum(&file).unwrap();
+        d.own_writes.insert(fpath.to_string(), (hash, long_ago));
+        assert!(!own_write(&mut d, &written(fpath, None)));
+        assert!(d.own_writes.is_empty());
+    }
+
+    #[test]
+    fn own_writes_are_recognised_by_modifier() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut d = daemon(dir.path());
+        let file = dir.path().join("file");
+        let fpath = file.to_str().unwrap();
+        std::fs::write(&file, "restored\n").unwrap();
+
+        let pid = std::process::id() as i32;
+        assert!(own_write(&mut d, &written(fpath, Some(pid))));
+        assert!(!own_write(&mut d, &written(fpath, Some(1))));
     }
-    state.save(INDEX).unwrap();
 }
@@ -0,0 +1,9 @@
+//! Library for controlling a running fwatchd, as fwatchctl does.
+//!
+//! [`socket`] holds the messages exchanged over the control socket and
+//! [`Client`] sends them, returning structured results.
+
+pub mod client;
+pub mod socket;
+
+pub use client::{Client, Error};
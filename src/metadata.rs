@@ -0,0 +1,105 @@
+use crate::socket::FileMetadata;
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use nix::errno::Errno;
+use nix::unistd::{chown, geteuid, Gid, Uid};
+use std::collections::BTreeMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Whether the filesystem does not support extended attributes at all.
+fn unsupported(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(Errno::ENOTSUP as i32)
+}
+
+/// Capture the metadata of a file, following symlinks like the content.
+/// Files on filesystems without extended attributes are captured without.
+pub fn capture(path: &Path) -> Result<FileMetadata> {
+    let meta = std::fs::metadata(path).context(format!("Failed to stat {}", path.display()))?;
+
+    let mut xattrs = BTreeMap::new();
+    let names = match xattr::list_deref(path) {
+        Ok(names) => names.collect(),
+        Err(e) if unsupported(&e) => vec![],
+        Err(e) => {
+            return Err(e).context(format!("Failed to list xattrs of {}", path.display()));
+        }
+    };
+    for name in names {
+        let key = name.to_string_lossy().to_string();
+        if let Some(value) = xattr::get_deref(path, &name)
+            .context(format!("Failed to read xattr {key} of {}", path.display()))?
+        {
+            xattrs.insert(key, value);
+        }
+    }
+
+    Ok(FileMetadata {
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        xattrs,
+    })
+}
+
+/// Apply the metadata to a file. Everything which can be applied is, the
+/// failures are reported together. Unless running as root, the daemon can
+/// neither give files away nor set every xattr, so only failing to apply
+/// the mode fails then, other failures are warned about.
+pub fn apply(path: &Path, meta: &FileMetadata) -> Result<()> {
+    let mut failures = vec![];
+    // Failures which are expected when not running as root
+    let mut denied = vec![];
+
+    // Ownership first, since changing it may clear the setuid/setgid bits
+    if let Err(e) = chown(
+        path,
+        Some(Uid::from_raw(meta.uid)),
+        Some(Gid::from_raw(meta.gid)),
+    ) {
+        denied.push(format!("owner {}:{}, {}", meta.uid, meta.gid, e));
+    }
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(meta.mode)) {
+        failures.push(format!("mode {:o}, {}", meta.mode, e));
+    }
+
+    match xattr::list_deref(path) {
+        Ok(names) => {
+            for name in names {
+                let key = name.to_string_lossy();
+                if !meta.xattrs.contains_key(key.as_ref()) {
+                    if let Err(e) = xattr::remove_deref(path, &name) {
+                        denied.push(format!("removing xattr {key}, {e}"));
+                    }
+                }
+            }
+        }
+        Err(e) if unsupported(&e) && meta.xattrs.is_empty() => {}
+        Err(e) => denied.push(format!("listing xattrs, {e}")),
+    }
+    for (key, value) in &meta.xattrs {
+        if let Err(e) = xattr::set_deref(path, key, value) {
+            denied.push(format!("xattr {key}, {e}"));
+        }
+    }
+
+    if geteuid().is_root() {
+        failures.append(&mut denied);
+    } else if !denied.is_empty() {
+        warn!(
+            "Could not restore all metadata of {}: {}",
+            path.display(),
+            denied.join("; ")
+        );
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Failed to restore metadata of {}: {}",
+            path.display(),
+            failures.join("; ")
+        ))
+    }
+}
@@ -0,0 +1,276 @@
+use crate::socket::{Action, Command};
+use crate::store::Store;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tiny_http::Header;
+
+/// Upper bounds of the buckets of index save latency, in seconds.
+const SAVE_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// How long the size of the store is reused while the number of snapshots
+/// stays the same, as measuring it walks the whole store.
+const STORE_SIZE_TTL: Duration = Duration::from_secs(60);
+
+struct Histogram {
+    /// Cumulative, as exposed
+    buckets: [u64; SAVE_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Index saves are observed wherever the index is saved, which is in more
+/// places than have the daemon at hand.
+static INDEX_SAVES: Mutex<Histogram> = Mutex::new(Histogram {
+    buckets: [0; SAVE_BUCKETS.len()],
+    count: 0,
+    sum: 0.0,
+});
+
+pub fn index_saved(elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    if let Ok(mut h) = INDEX_SAVES.lock() {
+        for (bucket, le) in h.buckets.iter_mut().zip(SAVE_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += secs;
+    }
+}
+
+/// Values which are read off the daemon when the metrics are published.
+pub struct Gauges {
+    pub files: usize,
+    pub snapshots: usize,
+    pub watched: usize,
+}
+
+/// Counters of what the daemon did since it started, published in the
+/// Prometheus text format over HTTP, to a file for the textfile collector
+/// of node_exporter, or not at all.
+#[derive(Default)]
+pub struct Metrics {
+    events: BTreeMap<&'static str, u64>,
+    actions: BTreeMap<(&'static str, &'static str), u64>,
+    requests: BTreeMap<String, u64>,
+    // snapshots when measured, when, bytes
+    store_size: Option<(usize, Instant, u64)>,
+    /// The latest metrics, served by the listener
+    served: Option<Arc<Mutex<String>>>,
+    file: Option<PathBuf>,
+    written: String,
+}
+
+fn serve(server: tiny_http::Server, served: Arc<Mutex<String>>, content_type: Header) {
+    for req in server.incoming_requests() {
+        let res = match req.url() {
+            "/metrics" => {
+                let body = served.lock().map_or_else(|_| String::new(), |s| s.clone());
+                tiny_http::Response::from_string(body).with_header(content_type.clone())
+            }
+            _ => tiny_http::Response::from_string("Not found\n").with_status_code(404),
+        };
+        if let Err(e) = req.respond(res) {
+            debug!("Failed to respond to metrics request, {}", e);
+        }
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn action_kind(action: &Action) -> &'static str {
+    match action {
+        Action::Save => "save",
+        Action::Script(_) => "script",
+        Action::Webhook(_) => "webhook",
+    }
+}
+
+impl Metrics {
+    /// Serve the metrics on `listen`, e.g. `127.0.0.1:9469`, and/or write
+    /// them to `file`. Must be called after daemonizing, the listener runs
+    /// on a thread of its own.
+    pub fn new(listen: Option<&str>, file: Option<&str>) -> Result<Metrics> {
+        let served = match listen {
+            Some(addr) => {
+                let server = tiny_http::Server::http(addr)
+                    .map_err(|e| anyhow!("Failed to listen for metrics on {addr}, {e}"))?;
+                info!("Serving metrics on http://{}/metrics", addr);
+                let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                    .map_err(|_| anyhow!("Invalid metrics Content-Type"))?;
+                let served = Arc::new(Mutex::new(String::new()));
+                let shared = Arc::clone(&served);
+                std::thread::spawn(move || serve(server, shared, content_type));
+                Some(served)
+            }
+            None => None,
+        };
+        Ok(Metrics {
+            served,
+            file: file.map(PathBuf::from),
+            ..Metrics::default()
+        })
+    }
+
+    pub fn event(&mut self, kind: &'static str) {
+        *self.events.entry(kind).or_default() += 1;
+    }
+
+    pub fn action(&mut self, action: &Action, ok: bool) {
+        let result = if ok { "run" } else { "failed" };
+        *self
+            .actions
+            .entry((action_kind(action), result))
+            .or_default() += 1;
+    }
+
+    pub fn request(&mut self, command: &Command) {
+        *self.requests.entry(format!("{command:?}")).or_default() += 1;
+    }
+
+    fn store_bytes(&mut self, store: &Store, snapshots: usize) -> u64 {
+        if let Some((n, at, bytes)) = self.store_size {
+            if n == snapshots && at.elapsed() < STORE_SIZE_TTL {
+                return bytes;
+            }
+        }
+        let bytes = store.size().unwrap_or_else(|e| {
+            error!("{:#}", e);
+            0
+        });
+        self.store_size = Some((snapshots, Instant::now(), bytes));
+        bytes
+    }
+
+    fn render(&mut self, gauges: &Gauges, store: &Store) -> String {
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "fwatchd_events_total",
+            "counter",
+            "Events received from the watcher, by type.",
+        );
+        for kind in ["written", "metadata", "lost"] {
+            let n = self.events.get(kind).copied().unwrap_or(0);
+            let _ = writeln!(out, "fwatchd_events_total{{type=\"{kind}\"}} {n}");
+        }
+
+        family(
+            &mut out,
+            "fwatchd_actions_total",
+            "counter",
+            "Actions run on changes and restores, by kind and result.",
+        );
+        for kind in ["save", "script", "webhook"] {
+            for result in ["run", "failed"] {
+                let n = self.actions.get(&(kind, result)).copied().unwrap_or(0);
+                let _ = writeln!(
+                    out,
+                    "fwatchd_actions_total{{kind=\"{kind}\",result=\"{result}\"}} {n}"
+                );
+            }
+        }
+
+        family(
+            &mut out,
+            "fwatchd_requests_total",
+            "counter",
+            "Requests received on the control socket, by command.",
+        );
+        for (command, n) in &self.requests {
+            let _ = writeln!(out, "fwatchd_requests_total{{command=\"{command}\"}} {n}");
+        }
+
+        family(&mut out, "fwatchd_tracked_files", "gauge", "Files tracked.");
+        let _ = writeln!(out, "fwatchd_tracked_files {}", gauges.files);
+
+        family(&mut out, "fwatchd_snapshots", "gauge", "Snapshots stored.");
+        let _ = writeln!(out, "fwatchd_snapshots {}", gauges.snapshots);
+
+        family(
+            &mut out,
+            "fwatchd_store_bytes",
+            "gauge",
+            "Bytes used by the snapshot store.",
+        );
+        let _ = writeln!(
+            out,
+            "fwatchd_store_bytes {}",
+            self.store_bytes(store, gauges.snapshots)
+        );
+
+        family(
+            &mut out,
+            "fwatchd_watches",
+            "gauge",
+            "Watches of tracked files, by state.",
+        );
+        let failed = gauges.files.saturating_sub(gauges.watched);
+        let _ = writeln!(
+            out,
+            "fwatchd_watches{{state=\"active\"}} {}",
+            gauges.watched
+        );
+        let _ = writeln!(out, "fwatchd_watches{{state=\"failed\"}} {failed}");
+
+        family(
+            &mut out,
+            "fwatchd_index_save_seconds",
+            "histogram",
+            "Time taken to save the index.",
+        );
+        if let Ok(h) = INDEX_SAVES.lock() {
+            for (n, le) in h.buckets.iter().zip(SAVE_BUCKETS) {
+                let _ = writeln!(out, "fwatchd_index_save_seconds_bucket{{le=\"{le}\"}} {n}");
+            }
+            let _ = writeln!(
+                out,
+                "fwatchd_index_save_seconds_bucket{{le=\"+Inf\"}} {}",
+                h.count
+            );
+            let _ = writeln!(out, "fwatchd_index_save_seconds_sum {}", h.sum);
+            let _ = writeln!(out, "fwatchd_index_save_seconds_count {}", h.count);
+        }
+        out
+    }
+
+    /// Write the textfile through a temporary file, so that the collector
+    /// never reads a partial one.
+    fn write(&self, path: &Path, text: &str) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, text).context(format!("Failed to write {}", path.display()))?;
+        std::fs::rename(&tmp, path).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Update whatever the metrics are published to.
+    pub fn publish(&mut self, gauges: &Gauges, store: &Store) {
+        if self.served.is_none() && self.file.is_none() {
+            return;
+        }
+        let text = self.render(gauges, store);
+        if let Some(served) = &self.served {
+            if let Ok(mut s) = served.lock() {
+                s.clone_from(&text);
+            }
+        }
+        if let Some(path) = &self.file {
+            if text != self.written {
+                match self.write(path, &text) {
+                    Ok(_) => self.written = text,
+                    Err(e) => error!("{:#}", e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use crate::server::Peer;
+use crate::socket::{
+    Action, Alias, AuditQuery, Cat, Command, Diff, Export, ForceSnapshot, NewCheckpoint, Packet,
+    Pin, Select, Tag, Track,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A rule grants the listed commands to the matching peers, for paths
+/// under any of the listed prefixes. An empty list matches anything.
+/// Tracking with a script or a webhook, which the daemon runs or posts to
+/// with its own privileges, must be granted explicitly.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub commands: Vec<Command>,
+    pub paths: Vec<PathBuf>,
+    /// Allow script actions and aliases
+    pub allow_exec: bool,
+    /// Allow webhook actions
+    pub allow_webhook: bool,
+}
+
+/// What a request has the daemon do on behalf of the peer, besides
+/// keeping snapshots.
+#[derive(Debug, Default)]
+struct Privileges {
+    exec: bool,
+    webhook: bool,
+}
+
+/// Authorization of control socket requests based on the credentials of
+/// the connecting peer. A request is allowed if any rule allows it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Rule {
+    fn matches_peer(&self, peer: &Peer) -> bool {
+        if self.uids.is_empty() && self.gids.is_empty() {
+            return true;
+        }
+        self.uids.contains(&peer.uid) || self.gids.contains(&peer.gid)
+    }
+
+    fn matches_command(&self, command: &Command) -> bool {
+        self.commands.is_empty() || self.commands.contains(command)
+    }
+
+    fn matches_paths(&self, paths: &[PathBuf]) -> bool {
+        self.paths.is_empty()
+            || paths
+                .iter()
+                .all(|p| self.paths.iter().any(|prefix| p.starts_with(prefix)))
+    }
+
+    fn grants(&self, privileges: &Privileges) -> bool {
+        (!privileges.exec || self.allow_exec) && (!privileges.webhook || self.allow_webhook)
+    }
+}
+
+impl Policy {
+    pub fn load(f: &str) -> Result<Policy> {
+        let policy = serde_json::from_reader::<std::fs::File, Self>(
+            std::fs::File::open(f).context(format!("Could not open policy {f}"))?,
+        )
+        .context(format!("Failed to parse policy {f}"))?;
+
+        Ok(policy)
+    }
+
+    /// Check whether the peer may issue the request, the reason is
+    /// returned when it is not allowed.
+    pub fn check(&self, peer: Option<&Peer>, pkt: &Packet) -> std::result::Result<(), String> {
+        let peer = peer.ok_or_else(|| "peer credentials are unavailable".to_string())?;
+        // The daemon user may always control itself
+        if peer.uid == 0 || peer.uid == nix::unistd::geteuid().as_raw() {
+            return Ok(());
+        }
+
+        let paths = request_paths(pkt).map_err(|e| format!("{:#}", e))?;
+        let privileges = request_privileges(pkt).map_err(|e| format!("{:#}", e))?;
+        let allowed = self.rules.iter().any(|r| {
+            r.matches_peer(peer)
+                && r.matches_command(&pkt.command)
+                && r.matches_paths(&paths)
+                && r.grants(&privileges)
+        });
+
+        if allowed {
+            Ok(())
+        } else {
+            let with = match privileges {
+                Privileges { exec: true, .. } => " with a script",
+                Privileges { webhook: true, .. } => " with a webhook",
+                _ => "",
+            };
+            Err(format!(
+                "no rule allows {:?}{} on {:?}",
+                pkt.command, with, paths
+            ))
+        }
+    }
+}
+
+fn normalize(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| Path::new(path).to_path_buf())
+}
+
+/// The paths a request operates on.
+fn request_paths(pkt: &Packet) -> Result<Vec<PathBuf>> {
+    let paths = match pkt.command {
+        Command::Echo | Command::Echoerr | Command::Status => vec![],
+        Command::History => vec![pkt.decode::<String>()?],
+        Command::List => match pkt.decode::<String>()?.as_str() {
+            "*" => vec![String::from("/")],
+            fname => vec![fname.to_string()],
+        },
+        Command::Audit => vec![pkt
+            .decode::<AuditQuery>()?
+            .path
+            .unwrap_or_else(|| String::from("/"))],
+        Command::Track => vec![pkt.decode::<Track>()?.fpath],
+        Command::Select => vec![pkt.decode::<Select>()?.fpath],
+        Command::Cat => vec![pkt.decode::<Cat>()?.fpath],
+        Command::Diff => vec![pkt.decode::<Diff>()?.fpath],
+        Command::Tag => vec![pkt.decode::<Tag>()?.fpath],
+        Command::Pin => vec![pkt.decode::<Pin>()?.fpath],
+        Command::Checkpoint => {
+            let files = pkt.decode::<NewCheckpoint>()?.files;
+            if files.is_empty() {
+                vec![String::from("/")]
+            } else {
+                files
+            }
+        }
+        Command::Export => {
+            let files = pkt.decode::<Export>()?.files;
+            if files.is_empty() {
+                vec![String::from("/")]
+            } else {
+                files
+            }
+        }
+        // Checkpoints are known by name only, and archives are only known
+        // once read, either may cover any file
+        Command::Checkpoints | Command::RestoreCheckpoint | Command::Import => {
+            vec![String::from("/")]
+        }
+        Command::Snapshot => vec![pkt
+            .decode::<ForceSnapshot>()?
+            .fpath
+            .unwrap_or_else(|| String::from("/"))],
+        Command::Subscribe => {
+            let filters = pkt.decode::<Vec<String>>()?;
+            if filters.is_empty() {
+                vec![String::from("/")]
+            } else {
+                filters
+            }
+        }
+    };
+
+    Ok(paths.iter().map(|p| normalize(p)).collect())
+}
+
+/// Scripts and webhooks the request would have the daemon run or post to.
+fn request_privileges(pkt: &Packet) -> Result<Privileges> {
+    if pkt.command != Command::Track {
+        return Ok(Privileges::default());
+    }
+    let track = pkt.decode::<Track>()?;
+    Ok(Privileges {
+        exec: matches!(track.action, Action::Script(_)) || matches!(track.alias, Alias::Script(_)),
+        webhook: matches!(track.action, Action::Webhook(_)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Neither root nor the daemon user, which are always allowed.
+    const UID: u32 = 4242;
+    const GID: u32 = 4343;
+
+    fn peer(uid: u32, gid: u32) -> Peer {
+        Peer { pid: 1, uid, gid }
+    }
+
+    fn packet<T: Serialize>(command: Command, payload: &T) -> Packet {
+        Packet {
+            command,
+            payload: bincode::serialize(payload).unwrap(),
+        }
+    }
+
+    fn list(path: &str) -> Packet {
+        packet(Command::List, &path.to_string())
+    }
+
+    fn rule(paths: &[&str]) -> Rule {
+        Rule {
+            uids: vec![UID],
+            paths: paths.iter().map(PathBuf::from).collect(),
+            ..Rule::default()
+        }
+    }
+
+    #[test]
+    fn paths_match_whole_components() {
+        let r = rule(&["/home/alice"]);
+        assert!(r.matches_paths(&[PathBuf::from("/home/alice")]));
+        assert!(r.matches_paths(&[PathBuf::from("/home/alice/.bashrc")]));
+        assert!(!r.matches_paths(&[PathBuf::from("/home/alice2/.bashrc")]));
+        assert!(!r.matches_paths(&[PathBuf::from("/home")]));
+        assert!(!r.matches_paths(&[PathBuf::from("/")]));
+    }
+
+    #[test]
+    fn all_paths_must_match() {
+        let r = rule(&["/home/alice", "/srv"]);
+        assert!(r.matches_paths(&[PathBuf::from("/home/alice/a"), PathBuf::from("/srv/b")]));
+        assert!(!r.matches_paths(&[PathBuf::from("/home/alice/a"), PathBuf::from("/etc/b")]));
+        assert!(rule(&[]).matches_paths(&[PathBuf::from("/etc/shadow")]));
+    }
+
+    #[test]
+    fn check_peer_command_and_path() {
+        let policy = Policy {
+            rules: vec![Rule {
+                commands: vec![Command::List, Command::Subscribe],
+                ..rule(&["/home/alice"])
+            }],
+        };
+        let alice = peer(UID, GID);
+        assert!(policy.check(Some(&alice), &list("/home/alice/a")).is_ok());
+        assert!(policy.check(Some(&alice), &list("/home/alice2/a")).is_err());
+        assert!(policy.check(Some(&alice), &list("*")).is_err());
+        assert!(policy
+            .check(Some(&alice), &packet(Command::History, &"/home/alice/a"))
+            .is_err());
+        assert!(policy
+            .check(Some(&peer(UID + 1, GID)), &list("/home/alice/a"))
+            .is_err());
+        assert!(policy.check(None, &list("/home/alice/a")).is_err());
+
+        let subscribe = |filters: &[&str]| {
+            let filters: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+            packet(Command::Subscribe, &filters)
+        };
+        assert!(policy
+            .check(Some(&alice), &subscribe(&["/home/alice"]))
+            .is_ok());
+        assert!(policy.check(Some(&alice), &subscribe(&[])).is_err());
+        assert!(policy
+            .check(Some(&alice), &subscribe(&["/home/alice", "/home/bob"]))
+            .is_err());
+    }
+
+    #[test]
+    fn check_by_group() {
+        let policy = Policy {
+            rules: vec![Rule {
+                gids: vec![GID],
+                ..Rule::default()
+            }],
+        };
+        assert!(policy.check(Some(&peer(UID, GID)), &list("/etc")).is_ok());
+        assert!(policy
+            .check(Some(&peer(UID, GID + 1)), &list("/etc"))
+            .is_err());
+        assert!(Policy::default()
+            .check(Some(&peer(UID, GID)), &list("/etc"))
+            .is_err());
+    }
+
+    fn track(alias: Alias, action: Action) -> Packet {
+        let track = Track {
+            fpath: String::from("/home/alice/a"),
+            alias,
+            action,
+        };
+        packet(Command::Track, &track)
+    }
+
+    #[test]
+    fn scripts_must_be_allowed() {
+        let alice = peer(UID, GID);
+        let policy = Policy {
+            rules: vec![rule(&["/home/alice"])],
+        };
+        let save = track(Alias::Basename, Action::Save);
+        let action = track(Alias::Basename, Action::Script(String::from("/bin/sh")));
+        let alias = track(Alias::Script(String::from("/bin/sh")), Action::Save);
+        assert!(policy.check(Some(&alice), &save).is_ok());
+        assert!(policy.check(Some(&alice), &action).is_err());
+        assert!(policy.check(Some(&alice), &alias).is_err());
+
+        let policy = Policy {
+            rules: vec![Rule {
+                allow_exec: true,
+                ..rule(&["/home/alice"])
+            }],
+        };
+        assert!(policy.check(Some(&alice), &action).is_ok());
+        assert!(policy.check(Some(&alice), &alias).is_ok());
+        assert!(policy.check(Some(&peer(UID + 1, GID)), &action).is_err());
+    }
+
+    #[test]
+    fn webhooks_must_be_allowed() {
+        let alice = peer(UID, GID);
+        let webhook = track(
+            Alias::Basename,
+            Action::Webhook(String::from("http://localhost/hook")),
+        );
+        let policy = Policy {
+            rules: vec![Rule {
+                allow_exec: true,
+                ..rule(&[])
+            }],
+        };
+        assert!(policy.check(Some(&alice), &webhook).is_err());
+
+        let policy = Policy {
+            rules: vec![Rule {
+                allow_webhook: true,
+                ..rule(&[])
+            }],
+        };
+        assert!(policy.check(Some(&alice), &webhook).is_ok());
+        let script = track(Alias::Basename, Action::Script(String::from("/bin/sh")));
+        assert!(policy.check(Some(&alice), &script).is_err());
+    }
+
+    #[test]
+    fn root_is_always_allowed() {
+        assert!(Policy::default()
+            .check(Some(&peer(0, 0)), &list("/etc/shadow"))
+            .is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::socket::{Packet, Response};
+use log::{debug, error, warn};
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Credentials of the process on the other end of the socket.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {} uid {} gid {}", self.pid, self.uid, self.gid)
+    }
+}
+
+impl Peer {
+    #[cfg(target_os = "linux")]
+    fn of(stream: &UnixStream) -> Option<Peer> {
+        match getsockopt(stream.as_raw_fd(), PeerCredentials) {
+            Ok(cred) => Some(Peer {
+                pid: cred.pid(),
+                uid: cred.uid(),
+                gid: cred.gid(),
+            }),
+            Err(e) => {
+                warn!("Failed to read peer credentials, {}", e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn of(_stream: &UnixStream) -> Option<Peer> {
+        None
+    }
+}
+
+/// A request which has been completely read from a client.
+pub struct Request {
+    pub stream: UnixStream,
+    pub pkt: Packet,
+    pub peer: Option<Peer>,
+}
+
+/// Accepts clients on the control socket and reads their requests on
+/// separate threads, so that a slow or stalled client never blocks the
+/// event loop. Requests are handed back to the event loop, which is woken
+/// through [`Server::wake_fd`].
+pub struct Server {
+    listener: UnixListener,
+    timeout: Duration,
+    tx: Sender<Request>,
+    rx: Receiver<Request>,
+    wake_tx: UnixStream,
+    wake_rx: UnixStream,
+}
+
+impl Server {
+    pub fn new(listener: UnixListener, timeout: Duration) -> std::io::Result<Server> {
+        let (tx, rx) = channel();
+        let (wake_tx, wake_rx) = UnixStream::pair()?;
+        wake_rx.set_nonblocking(true)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Server {
+            listener,
+            timeout,
+            tx,
+            rx,
+            wake_tx,
+            wake_rx,
+        })
+    }
+
+    pub fn listen_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    pub fn wake_fd(&self) -> RawFd {
+        self.wake_rx.as_raw_fd()
+    }
+
+    /// Wakes the event loop when written to. Signals may be delivered to
+    /// any thread, so their handlers write to one of these rather than
+    /// rely on interrupting the poll of the event loop.
+    pub fn waker(&self) -> std::io::Result<UnixStream> {
+        self.wake_tx.try_clone()
+    }
+
+    /// Accept all pending clients, each client is read on its own thread.
+    pub fn accept(&self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Failed to accept client, {}", e);
+                    return;
+                }
+            };
+
+            let wake = match self.wake_tx.try_clone() {
+                Ok(wake) => wake,
+                Err(e) => {
+                    error!("Failed to clone wake socket, {}", e);
+                    return;
+                }
+            };
+            let tx = self.tx.clone();
+            let timeout = self.timeout;
+            thread::spawn(move || receive(stream, timeout, tx, wake));
+        }
+    }
+
+    /// Collect the requests which have been read since the last call.
+    pub fn requests(&self) -> Vec<Request> {
+        let mut buf = [0; 64];
+        while let Ok(n) = (&self.wake_rx).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        self.rx.try_iter().collect()
+    }
+
+    /// Write the response on a separate thread and close the connection.
+    pub fn respond(&self, mut stream: UnixStream, resp: Response) {
+        let resp = match bincode::serialize(&resp) {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Failed to serialize response, {}", e);
+                return;
+            }
+        };
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            if let Err(e) = stream
+                .set_write_timeout(Some(timeout))
+                .and_then(|_| stream.write_all(&resp))
+            {
+                warn!("Failed to write response to client, {}", e);
+            }
+        });
+    }
+}
+
+fn receive(stream: UnixStream, timeout: Duration, tx: Sender<Request>, mut wake: UnixStream) {
+    if let Err(e) = stream.set_nonblocking(false) {
+        error!("Failed to make client blocking, {}", e);
+        return;
+    }
+    if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+        error!("Failed to set client timeout, {}", e);
+        return;
+    }
+
+    let peer = Peer::of(&stream);
+    match Packet::read_from(&stream) {
+        Ok(pkt) => {
+            debug!("Received request {:?}", pkt.command);
+            if tx.send(Request { stream, pkt, peer }).is_ok() {
+                let _ = wake.write_all(&[0]);
+            }
+        }
+        Err(e) => warn!("Failed to read request from client, {}", e),
+    }
+}
@@ -1,6 +1,15 @@
+use bincode::Options;
 use log::{Level, Log, Metadata, Record};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Upper bound on the size of a request, to not allocate whatever
+/// a misbehaving client claims it is sending.
+pub const MAX_PACKET_SIZE: u64 = 16 * 1024 * 1024;
 
 pub struct StdoutLog {
     pub level: Level,
@@ -18,15 +27,69 @@ impl Log for StdoutLog {
     fn flush(&self) {}
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Command {
     Echoerr,
     Echo,
     List,
     Track,
     Select,
+    Subscribe,
+    Audit,
+    History,
+    Cat,
+    Tag,
+    Pin,
+    Snapshot,
+    Checkpoint,
+    Checkpoints,
+    RestoreCheckpoint,
+    Export,
+    Import,
+    Diff,
+    Status,
+}
+
+/// Why a request failed, for clients to tell failures apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    Failed,
+    InvalidRequest,
+    PermissionDenied,
+    NotTracked,
+    /// No single version matches a hash, alias, label, number or time
+    BadVersion,
+}
+
+/// The response to every request. A subscription responds with `Ok` before
+/// the stream of events.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok(Vec<u8>),
+    Err(ErrorKind, String),
+}
+
+/// A failure of a known kind, which the daemon reports as such.
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub message: String,
 }
 
+impl Failure {
+    pub fn new(kind: ErrorKind, message: String) -> Failure {
+        Failure { kind, message }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Alias {
     Script(String),
@@ -40,10 +103,33 @@ pub struct Packet {
     pub payload: Vec<u8>,
 }
 
+impl Packet {
+    /// Read exactly one packet, as written by `bincode::serialize`.
+    pub fn read_from<R: Read>(reader: R) -> bincode::Result<Packet> {
+        bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_PACKET_SIZE)
+            .deserialize_from(reader)
+    }
+
+    /// The payload as the request type of the command. Neither may be
+    /// trusted, the payload is whatever the client sent.
+    pub fn decode<T: DeserializeOwned>(&self) -> bincode::Result<T> {
+        bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_PACKET_SIZE)
+            .deserialize(&self.payload)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum Action {
     Save,
     Script(String),
+    /// Save a snapshot and POST a [`Notification`] to the URL
+    Webhook(String),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -53,12 +139,415 @@ pub struct Track {
     pub action: Action,
 }
 
+/// The process which modified a file, as reported by fanotify.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Modifier {
+    pub pid: i32,
+    pub exe: Option<String>,
+    pub uid: Option<u32>,
+}
+
+impl std::fmt::Display for Modifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pid {}", self.pid)?;
+        if let Some(exe) = &self.exe {
+            write!(f, " {}", exe)?;
+        }
+        if let Some(uid) = self.uid {
+            write!(f, " uid {}", uid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ownership, permissions and extended attributes of a file. The extended
+/// attributes include ACLs and SELinux labels.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SnapshotFormat")]
+pub struct Snapshot {
+    pub alias: String,
+    /// Where the content is stored, relative to the repository when
+    /// stored in git
+    pub path: String,
+    /// Commit holding the content, when stored in git
+    pub commit: Option<String>,
+    pub modifier: Option<Modifier>,
+    pub metadata: Option<FileMetadata>,
+    pub labels: Vec<String>,
+    pub note: Option<String>,
+    /// Pinned snapshots are never pruned
+    pub pinned: bool,
+}
+
+#[derive(Deserialize)]
+struct SnapshotFields {
+    alias: String,
+    path: String,
+    #[serde(default)]
+    commit: Option<String>,
+    #[serde(default)]
+    modifier: Option<Modifier>,
+    #[serde(default)]
+    metadata: Option<FileMetadata>,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(default)]
+    note: Option<String>,
+    #[serde(default)]
+    pinned: bool,
+}
+
+/// Snapshots used to be stored as (alias, path) tuples, which are
+/// still accepted when loading an index.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SnapshotFormat {
+    Tuple(String, String),
+    Fields(SnapshotFields),
+}
+
+impl From<SnapshotFormat> for Snapshot {
+    fn from(f: SnapshotFormat) -> Snapshot {
+        match f {
+            SnapshotFormat::Tuple(alias, path) => Snapshot {
+                alias,
+                path,
+                commit: None,
+                modifier: None,
+                metadata: None,
+                labels: vec![],
+                note: None,
+                pinned: false,
+            },
+            SnapshotFormat::Fields(f) => Snapshot {
+                alias: f.alias,
+                path: f.path,
+                commit: f.commit,
+                modifier: f.modifier,
+                metadata: f.metadata,
+                labels: f.labels,
+                note: f.note,
+                pinned: f.pinned,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RevisionKind {
+    Saved,
+    /// The file was restored from the snapshot by a select
+    Restored,
+}
+
+/// A point in the history of a tracked file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub time: u64,
+    pub hash: String,
+    pub kind: RevisionKind,
+}
+
+/// Numbered versions of a file, the distinct hashes in the order they
+/// were first seen. Version N is at index N - 1.
+pub fn versions(history: &[Revision]) -> Vec<&str> {
+    let mut versions: Vec<&str> = vec![];
+    for rev in history {
+        if !versions.contains(&rev.hash.as_str()) {
+            versions.push(&rev.hash);
+        }
+    }
+    versions
+}
+
+/// Reference to a snapshot of a tracked file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Version {
+    /// A hash, a unique prefix of one, or the alias or a label of a snapshot
+    Hash(String),
+    /// The version before the current one
+    Prev,
+    /// Numbered as by [`versions`], counting from 1
+    Number(usize),
+    /// The version which was current at the given time
+    At(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Select {
+    pub fpath: String,
+    pub version: Version,
+    /// Run the script action of the file after restoring it
+    pub run_hooks: bool,
+}
+
+/// Add or remove labels of a snapshot, and replace its note. An empty
+/// note removes it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tag {
+    pub fpath: String,
+    pub version: Version,
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pin {
+    pub fpath: String,
+    pub version: Version,
+    pub pinned: bool,
+}
+
+/// Take a snapshot of a file now, or of every tracked file when `fpath`
+/// is `None`. The message becomes the note of the snapshots.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForceSnapshot {
+    pub fpath: Option<String>,
+    pub message: Option<String>,
+}
+
+/// Versions of several files which were taken together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub time: u64,
+    pub message: Option<String>,
+    // path --> hash
+    pub files: BTreeMap<String, String>,
+}
+
+/// Snapshot the files, or every tracked file when there are none, and
+/// record their versions as a named checkpoint.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewCheckpoint {
+    pub name: String,
+    pub files: Vec<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestoreCheckpoint {
+    pub name: String,
+    /// Run the script actions of the files after restoring them
+    pub run_hooks: bool,
+}
+
+/// Request for an archive of the files, or of every tracked file when
+/// there are none.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Export {
+    pub files: Vec<String>,
+    /// Compress the archive with zstd
+    pub compress: bool,
+}
+
+/// Merge an archive, which the daemon reads from `path`, into the index.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Import {
+    pub path: String,
+}
+
+/// Request for a unified diff between two versions, or between a version
+/// and the current content when `to` is `None`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Diff {
+    pub fpath: String,
+    pub from: Version,
+    pub to: Option<Version>,
+}
+
+/// Request for the content of a snapshot.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cat {
+    pub fpath: String,
+    pub version: Version,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entry {
-    // hash --> filename
-    pub snapshots: HashMap<String, (String, String)>,
+    // hash --> snapshot
+    pub snapshots: HashMap<String, Snapshot>,
     pub action: Action,
     pub alias: Alias,
+    /// Hash of the file when it was last seen by the daemon
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Metadata of the file when it was last seen by the daemon
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    /// Oldest first
+    #[serde(default)]
+    pub history: Vec<Revision>,
+}
+
+/// A snapshot as listed by [`Command::List`], one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListItem {
+    pub path: String,
+    pub hash: String,
+    pub snapshot: Snapshot,
+    /// Whether the file currently has the content of this snapshot
+    pub current: bool,
+}
+
+/// A version of a tracked file. Response to [`Command::Track`], with the
+/// snapshot taken, and to [`Command::Select`], with the one restored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub path: String,
+    pub hash: String,
+}
+
+/// Snapshots taken together, and the checkpoint they were recorded as.
+/// Response to [`Command::Snapshot`] and [`Command::Checkpoint`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Taken {
+    pub checkpoint: Option<String>,
+    // path --> hash
+    pub files: BTreeMap<String, String>,
+}
+
+/// Response to [`Command::Status`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub version: String,
+    pub pid: u32,
+    /// When the daemon was started, seconds since the epoch
+    pub started: u64,
+    pub files: usize,
+    pub snapshots: usize,
+    pub checkpoints: usize,
+    pub subscribers: usize,
+    /// Where snapshots are stored
+    pub store: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EventKind {
+    Changed,
+    MetadataChanged,
+    Restored { hash: String },
+    Saved { hash: String, alias: String },
+    ActionRun(Action),
+    ActionFailed { action: Action, error: String },
+    WatchLost,
+}
+
+/// An event streamed to clients which have sent a [`Command::Subscribe`],
+/// one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub time: u64,
+    pub path: String,
+    pub kind: EventKind,
+}
+
+/// Lines changed between two versions of a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// The JSON body POSTed by an [`Action::Webhook`]. When the daemon has a
+/// webhook secret, the body is signed with HMAC-SHA256 in the
+/// `X-Fwatchd-Signature` header as `sha256=<hex>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    /// "change" or "restore", as told to scripts
+    pub event: String,
+    pub path: String,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    /// Against the previous snapshot, if its content is still stored
+    pub diff: Option<DiffSummary>,
+    pub host: String,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditEvent {
+    Track,
+    Change,
+    Metadata,
+    Select,
+    Snapshot,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: u64,
+    pub path: String,
+    pub event: AuditEvent,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    pub action: Option<Action>,
+    pub error: Option<String>,
+    /// The new metadata, for metadata changes
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    /// Credentials of the client, for events caused by socket commands
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+}
+
+impl AuditRecord {
+    pub fn new(path: &str, event: AuditEvent) -> AuditRecord {
+        AuditRecord {
+            time: unix_now(),
+            path: path.to_string(),
+            event,
+            old_hash: None,
+            new_hash: None,
+            action: None,
+            error: None,
+            metadata: None,
+            uid: None,
+            pid: None,
+        }
+    }
+}
+
+/// Selects audit records by path prefix, compared by whole components as
+/// the policy does, and an inclusive time range.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub path: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, rec: &AuditRecord) -> bool {
+        self.path
+            .as_ref()
+            .is_none_or(|p| Path::new(&rec.path).starts_with(p))
+            && self.since.is_none_or(|t| rec.time >= t)
+            && self.until.is_none_or(|t| rec.time <= t)
+    }
+}
+
+/// Seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 pub const SOCK_PATH: &str = "/var/run/fwatchd.socket";
+/// Overrides [`SOCK_PATH`] for both the daemon and its clients.
+pub const SOCK_ENV: &str = "FWATCHD_SOCKET";
+
+/// Socket of a per-user daemon, in `$XDG_RUNTIME_DIR`.
+pub fn user_socket() -> Option<std::path::PathBuf> {
+    dirs::runtime_dir().map(|dir| dir.join("fwatchd.socket"))
+}
@@ -0,0 +1,189 @@
+use crate::socket::Snapshot;
+use anyhow::{anyhow, Context, Result};
+use git2::{Commit, Index, IndexEntry, IndexTime, Oid, Repository, Signature};
+use log::warn;
+use std::path::Path;
+
+/// Where the content of snapshots is kept. Snapshots record where they
+/// were stored, so snapshots in files are still read after switching to
+/// git, snapshots in git only while git is used.
+pub enum Store {
+    /// A copy of each version next to the index
+    Files(String),
+    Git(GitStore),
+}
+
+impl Store {
+    pub fn files(dir: &str) -> Store {
+        Store::Files(dir.to_string())
+    }
+
+    pub fn git(path: &Path) -> Result<Store> {
+        Ok(Store::Git(GitStore::open(path)?))
+    }
+
+    /// Store the current content of `fpath`, returns the path of the
+    /// snapshot and the commit holding it, if any.
+    pub fn put(&self, fpath: &str, hash: &str, message: &str) -> Result<(String, Option<String>)> {
+        match self {
+            Store::Files(dir) => {
+                let target = format!("{}/{}-{}", dir, fpath, hash);
+                if let Some(parent) = Path::new(&target).parent() {
+                    std::fs::create_dir_all(parent)
+                        .context("Failed to create directory for target")?;
+                }
+                // Content is stored by hash, it may have been stored before
+                if !Path::new(&target).exists() {
+                    std::fs::copy(fpath, &target).context("Failed to save file version")?;
+                }
+                Ok((target, None))
+            }
+            Store::Git(git) => {
+                let content = std::fs::read(fpath).context(format!("Failed to read {fpath}"))?;
+                let (path, commit) = git.commit(fpath, &content, message)?;
+                Ok((path, Some(commit)))
+            }
+        }
+    }
+
+    pub fn read(&self, snapshot: &Snapshot) -> Result<Vec<u8>> {
+        match (&snapshot.commit, self) {
+            (None, _) => std::fs::read(&snapshot.path)
+                .context(format!("Failed to read snapshot {}", snapshot.path)),
+            (Some(commit), Store::Git(git)) => git.read(commit, &snapshot.path),
+            (Some(commit), Store::Files(_)) => Err(anyhow!(
+                "Snapshot is stored in git as {commit}:{}, which is not in use",
+                snapshot.path
+            )),
+        }
+    }
+
+    /// Bytes used by the store on disk.
+    pub fn size(&self) -> Result<u64> {
+        match self {
+            Store::Files(dir) => dir_size(Path::new(dir)),
+            Store::Git(git) => dir_size(git.repo.path()),
+        }
+    }
+
+    /// Remove the content of a pruned snapshot, commits are left alone.
+    pub fn remove(&self, snapshot: &Snapshot) {
+        if snapshot.commit.is_some() {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&snapshot.path) {
+            warn!("Failed to remove {}, {}", snapshot.path, e);
+        }
+    }
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+impl std::fmt::Display for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Store::Files(dir) => write!(f, "files {dir}"),
+            Store::Git(git) => write!(f, "git {}", git.repo.path().display()),
+        }
+    }
+}
+
+/// A bare repository mirroring the tracked paths, with a commit for every
+/// version which is saved.
+pub struct GitStore {
+    repo: Repository,
+}
+
+impl GitStore {
+    fn open(path: &Path) -> Result<GitStore> {
+        let repo = match Repository::open_bare(path) {
+            Ok(repo) => repo,
+            Err(_) => Repository::init_bare(path).context(format!(
+                "Failed to create git repository {}",
+                path.display()
+            ))?,
+        };
+        Ok(GitStore { repo })
+    }
+
+    /// Commit the content at the tracked path, without the leading `/`.
+    /// Content which is already committed at that path is not committed
+    /// again.
+    fn commit(&self, fpath: &str, content: &[u8], message: &str) -> Result<(String, String)> {
+        let path = fpath.trim_start_matches('/').to_string();
+        let parent = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit().context("Failed to read HEAD")?),
+            Err(_) => None,
+        };
+
+        let mut index = Index::new().context("Failed to create git index")?;
+        if let Some(parent) = &parent {
+            index
+                .read_tree(&parent.tree().context("Failed to read HEAD tree")?)
+                .context("Failed to read HEAD tree")?;
+        }
+        let blob = self
+            .repo
+            .blob(content)
+            .context("Failed to write git blob")?;
+        index
+            .add(&IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: content.len() as u32,
+                id: blob,
+                flags: 0,
+                flags_extended: 0,
+                path: path.clone().into_bytes(),
+            })
+            .context(format!("Failed to add {path} to git index"))?;
+        let tree_id = index
+            .write_tree_to(&self.repo)
+            .context("Failed to write git tree")?;
+
+        if let Some(parent) = &parent {
+            if parent.tree_id() == tree_id {
+                return Ok((path, parent.id().to_string()));
+            }
+        }
+        let tree = self.repo.find_tree(tree_id)?;
+        let sig = Signature::now("fwatchd", "fwatchd@localhost")?;
+        let parents: Vec<&Commit> = parent.iter().collect();
+        let id = self
+            .repo
+            .commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .context("Failed to commit to git repository")?;
+        Ok((path, id.to_string()))
+    }
+
+    fn read(&self, commit: &str, path: &str) -> Result<Vec<u8>> {
+        let commit = self
+            .repo
+            .find_commit(Oid::from_str(commit)?)
+            .context(format!("Found no commit {commit} in git repository"))?;
+        let blob = commit
+            .tree()?
+            .get_path(Path::new(path))
+            .and_then(|e| e.to_object(&self.repo))
+            .and_then(|o| o.peel_to_blob())
+            .context(format!("Found no {path} in commit {}", commit.id()))?;
+        Ok(blob.content().to_vec())
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use nix::sys::stat::{fstat, SFlag};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::time::Duration;
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Variables meant for this process only are only honoured when
+/// `<name>_PID` names this process, they may have been inherited from a
+/// parent which was passed them.
+fn for_us(pid_var: &str) -> bool {
+    match std::env::var(pid_var) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => false,
+    }
+}
+
+/// Keep variables read by the daemon from scripts it runs.
+fn unset(vars: &[&str]) {
+    for var in vars {
+        std::env::remove_var(var);
+    }
+}
+
+fn notify_addr(path: &str) -> Result<SocketAddr> {
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name).context("Invalid abstract NOTIFY_SOCKET")
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(anyhow!("Abstract NOTIFY_SOCKET is not supported")),
+        None => SocketAddr::from_pathname(path).context("Invalid NOTIFY_SOCKET"),
+    }
+}
+
+/// Tell the service manager about the state of the daemon, e.g.
+/// `READY=1` or `STATUS=...`. Does nothing unless started by a unit with
+/// `Type=notify`.
+pub fn notify(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let res = notify_addr(&path).and_then(|addr| {
+        let sock = UnixDatagram::unbound().context("Failed to create notify socket")?;
+        sock.send_to_addr(state.as_bytes(), &addr)
+            .context(format!("Failed to notify {path}"))
+    });
+    match res {
+        Ok(_) => debug!("Notified {}", state.replace('\n', " ")),
+        Err(e) => warn!("{:#}", e),
+    }
+}
+
+/// How often the service manager expects `WATCHDOG=1`, if at all. The
+/// service manager only sets `WATCHDOG_PID` when it may be ambiguous.
+pub fn watchdog_interval() -> Option<Duration> {
+    let ours = std::env::var_os("WATCHDOG_PID").is_none() || for_us("WATCHDOG_PID");
+    let usec = std::env::var("WATCHDOG_USEC").ok();
+    unset(&["WATCHDOG_PID", "WATCHDOG_USEC"]);
+    if !ours {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// The listening socket passed by socket activation, if any. It is taken
+/// over by the daemon, and not passed on to scripts.
+pub fn listener() -> Result<Option<UnixListener>> {
+    let ours = for_us("LISTEN_PID");
+    let fds = std::env::var("LISTEN_FDS");
+    unset(&["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"]);
+    if !ours {
+        return Ok(None);
+    }
+    let fds = match fds {
+        Ok(fds) => fds
+            .parse::<i32>()
+            .context(format!("Invalid LISTEN_FDS {fds}"))?,
+        Err(_) => return Ok(None),
+    };
+    if fds != 1 {
+        return Err(anyhow!("Expected one socket from LISTEN_FDS, got {fds}"));
+    }
+
+    let stat = fstat(LISTEN_FDS_START).context("Invalid socket from LISTEN_FDS")?;
+    if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFSOCK {
+        return Err(anyhow!("File descriptor from LISTEN_FDS is not a socket"));
+    }
+    nix::fcntl::fcntl(
+        LISTEN_FDS_START,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )
+    .context("Failed to set close-on-exec on socket from LISTEN_FDS")?;
+    Ok(Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) }))
+}
@@ -0,0 +1,289 @@
+use crate::socket::Modifier;
+use anyhow::{anyhow, Context, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Written,
+    /// Only ownership, permissions or extended attributes changed
+    Metadata,
+    /// The watch was removed, the file was likely deleted or replaced
+    Lost,
+}
+
+/// A change reported by a watcher.
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub modifier: Option<Modifier>,
+}
+
+/// Watches tracked files for writes. fanotify is used when the daemon is
+/// permitted to, since it reports which process modified the file, with
+/// inotify as the fallback. Metadata changes are only noticed by inotify.
+pub enum Watcher {
+    Inotify(InotifyWatcher),
+    Fanotify(FanotifyWatcher),
+}
+
+impl Watcher {
+    /// Must be called while the daemon is still privileged.
+    pub fn fanotify() -> Result<Watcher> {
+        Ok(Watcher::Fanotify(FanotifyWatcher::new()?))
+    }
+
+    pub fn inotify(persistent: bool) -> Result<Watcher> {
+        Ok(Watcher::Inotify(InotifyWatcher::new(persistent)?))
+    }
+
+    pub fn add(&mut self, path: &str) -> Result<()> {
+        match self {
+            Watcher::Inotify(w) => w.add(path),
+            Watcher::Fanotify(w) => w.add(path),
+        }
+    }
+
+    /// Number of tracked files currently watched.
+    pub fn watched(&self) -> usize {
+        let paths: HashSet<&String> = match self {
+            Watcher::Inotify(w) => w.wdm.values().collect(),
+            Watcher::Fanotify(w) => w.files.values().collect(),
+        };
+        paths.len()
+    }
+
+    pub fn read_events(&mut self) -> Vec<Change> {
+        match self {
+            Watcher::Inotify(w) => w.read_events(),
+            Watcher::Fanotify(w) => w.read_events(),
+        }
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Watcher::Inotify(w) => w.inotify.as_raw_fd(),
+            Watcher::Fanotify(w) => w.fd,
+        }
+    }
+}
+
+pub struct InotifyWatcher {
+    inotify: Inotify,
+    wdm: HashMap<WatchDescriptor, String>,
+    persistent: bool,
+}
+
+impl InotifyWatcher {
+    fn new(persistent: bool) -> Result<InotifyWatcher> {
+        Ok(InotifyWatcher {
+            inotify: Inotify::init().context("Failed to intialize inotify object")?,
+            wdm: HashMap::new(),
+            persistent,
+        })
+    }
+
+    fn add(&mut self, path: &str) -> Result<()> {
+        let wd = self
+            .inotify
+            .watches()
+            .add(path, WatchMask::CLOSE_WRITE | WatchMask::ATTRIB)
+            .context(format!("Failed to add watch for {path}"))?;
+        self.wdm.insert(wd, path.to_string());
+        Ok(())
+    }
+
+    fn read_events(&mut self) -> Vec<Change> {
+        let mut buffer = [0; 1024];
+        let events = match self.inotify.read_events(&mut buffer) {
+            Ok(events) => events,
+            Err(_) => return vec![],
+        };
+
+        let mut changes = vec![];
+        let mut ignored = vec![];
+        for e in events {
+            debug!("Processing inotify event {:?}", e);
+            let name = match self.wdm.get(&e.wd) {
+                Some(name) => name.clone(),
+                None => continue,
+            };
+            let kind = if e.mask.contains(EventMask::IGNORED) {
+                ignored.push((e.wd.clone(), name.clone()));
+                ChangeKind::Lost
+            } else if e.mask.contains(EventMask::ATTRIB) {
+                ChangeKind::Metadata
+            } else {
+                ChangeKind::Written
+            };
+            changes.push(Change {
+                path: name,
+                kind,
+                modifier: None,
+            });
+        }
+
+        for (wd, name) in ignored {
+            self.wdm.remove(&wd);
+            // The file was replaced and is already watched again, as after a restore
+            if self.wdm.values().any(|n| *n == name) {
+                changes.retain(|c| c.path != name || c.kind != ChangeKind::Lost);
+                continue;
+            }
+            if !self.persistent {
+                continue;
+            }
+            match self.add(&name) {
+                Ok(_) => {
+                    for c in changes
+                        .iter_mut()
+                        .filter(|c| c.path == name && c.kind == ChangeKind::Lost)
+                    {
+                        c.kind = ChangeKind::Written;
+                    }
+                }
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        changes
+    }
+}
+
+/// Marks the directories of tracked files, so that replacing a file by
+/// renaming over it is still noticed.
+pub struct FanotifyWatcher {
+    fd: RawFd,
+    // canonical path --> tracked path
+    files: HashMap<String, String>,
+}
+
+impl Drop for FanotifyWatcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl FanotifyWatcher {
+    fn new() -> Result<FanotifyWatcher> {
+        let fd = unsafe {
+            libc::fanotify_init(
+                libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+                (libc::O_RDONLY | libc::O_LARGEFILE) as u32,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow!(std::io::Error::last_os_error()))
+                .context("Failed to initialize fanotify, CAP_SYS_ADMIN is required");
+        }
+
+        Ok(FanotifyWatcher {
+            fd,
+            files: HashMap::new(),
+        })
+    }
+
+    fn add(&mut self, path: &str) -> Result<()> {
+        let dir = Path::new(path)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let cdir = CString::new(dir.as_os_str().as_bytes())?;
+        let ret = unsafe {
+            libc::fanotify_mark(
+                self.fd,
+                libc::FAN_MARK_ADD,
+                libc::FAN_CLOSE_WRITE | libc::FAN_EVENT_ON_CHILD,
+                libc::AT_FDCWD,
+                cdir.as_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(anyhow!(std::io::Error::last_os_error()))
+                .context(format!("Failed to add fanotify mark for {}", dir.display()));
+        }
+
+        let canonical = std::fs::canonicalize(path)
+            .map_or_else(|_| path.to_string(), |p| p.display().to_string());
+        self.files.insert(canonical, path.to_string());
+        Ok(())
+    }
+
+    fn read_events(&mut self) -> Vec<Change> {
+        let mut buffer = [0u8; 4096];
+        let mut changes = vec![];
+
+        loop {
+            let n = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if n <= 0 {
+                break;
+            }
+
+            let mut offset = 0;
+            let n = n as usize;
+            while offset + size_of::<libc::fanotify_event_metadata>() <= n {
+                let meta = unsafe {
+                    std::ptr::read_unaligned(
+                        buffer[offset..]
+                            .as_ptr()
+                            .cast::<libc::fanotify_event_metadata>(),
+                    )
+                };
+                if meta.vers != libc::FANOTIFY_METADATA_VERSION || meta.event_len == 0 {
+                    warn!("Unexpected fanotify metadata version {}", meta.vers);
+                    return changes;
+                }
+                offset += meta.event_len as usize;
+
+                if meta.mask & libc::FAN_Q_OVERFLOW != 0 {
+                    warn!("fanotify queue overflowed, events were lost");
+                }
+                if meta.fd == libc::FAN_NOFD {
+                    continue;
+                }
+
+                let path = std::fs::read_link(format!("/proc/self/fd/{}", meta.fd));
+                unsafe { libc::close(meta.fd) };
+                let path = match path {
+                    Ok(path) => path.display().to_string(),
+                    Err(_) => continue,
+                };
+
+                debug!("Processing fanotify event on {} by pid {}", path, meta.pid);
+                if let Some(tracked) = self.files.get(&path) {
+                    changes.push(Change {
+                        path: tracked.clone(),
+                        kind: ChangeKind::Written,
+                        modifier: Some(modifier(meta.pid)),
+                    });
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// Look up the process, it may already have exited.
+fn modifier(pid: i32) -> Modifier {
+    let exe = std::fs::read_link(format!("/proc/{pid}/exe"))
+        .ok()
+        .map(|p| p.display().to_string());
+    let uid = std::fs::read_to_string(format!("/proc/{pid}/status"))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|l| l.starts_with("Uid:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|uid| uid.parse().ok())
+        });
+
+    Modifier { pid, exe, uid }
+}
@@ -0,0 +1,210 @@
+use crate::socket::Notification;
+use anyhow::{Context, Result};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Delay before the first retry, doubled after every failed attempt.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    nix::unistd::gethostname(&mut buf)
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// A notification waiting to be delivered. The body is kept as sent, so
+/// that its signature and delivery id stay the same across retries.
+#[derive(Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    body: String,
+    attempts: u32,
+    /// Milliseconds since the epoch
+    due: u64,
+}
+
+struct Worker {
+    path: PathBuf,
+    pending: Vec<Delivery>,
+    secret: Option<Vec<u8>>,
+    retries: u32,
+    agent: ureq::Agent,
+}
+
+impl Worker {
+    fn save(&self) {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let res = serde_json::to_vec(&self.pending)
+            .context("Failed to serialize webhook outbox")
+            .and_then(|json| std::fs::write(&tmp, json).context("Failed to write webhook outbox"))
+            .and_then(|_| {
+                std::fs::rename(&tmp, &self.path).context("Failed to write webhook outbox")
+            });
+        if let Err(e) = res {
+            error!("{:#}", e);
+        }
+    }
+
+    fn post(&self, delivery: &Delivery) -> Result<()> {
+        let mut hasher = Sha256::new();
+        hasher.input(delivery.body.as_bytes());
+        let mut digest = [0u8; 32];
+        hasher.result(&mut digest);
+        let id = hex(&digest[..16]);
+        let mut req = self
+            .agent
+            .post(&delivery.url)
+            .set("Content-Type", "application/json")
+            .set("X-Fwatchd-Delivery", &id);
+        if let Some(secret) = &self.secret {
+            let mut mac = Hmac::new(Sha256::new(), secret);
+            mac.input(delivery.body.as_bytes());
+            let signature = format!("sha256={}", hex(mac.result().code()));
+            req = req.set("X-Fwatchd-Signature", &signature);
+        }
+        req.send_string(&delivery.body)
+            .map_err(Box::new)
+            .context(format!("Failed to notify {}", delivery.url))?;
+        Ok(())
+    }
+
+    /// When the next delivery is due. Deliveries to a URL are made in
+    /// order, so only the first one to each URL counts.
+    fn next_due(&self) -> Option<u64> {
+        let mut seen: Vec<&str> = vec![];
+        let mut next = None;
+        for d in &self.pending {
+            if seen.contains(&d.url.as_str()) {
+                continue;
+            }
+            seen.push(&d.url);
+            next = Some(next.map_or(d.due, |n: u64| n.min(d.due)));
+        }
+        next
+    }
+
+    fn deliver_due(&mut self) {
+        let mut blocked: Vec<String> = vec![];
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.pending.len() {
+            let d = &self.pending[i];
+            if blocked.contains(&d.url) || d.due > now_ms() {
+                blocked.push(d.url.clone());
+                i += 1;
+                continue;
+            }
+
+            changed = true;
+            match self.post(d) {
+                Ok(_) => {
+                    debug!("Notified {}", d.url);
+                    self.pending.remove(i);
+                }
+                Err(e) if d.attempts >= self.retries => {
+                    error!("{:#}, giving up after {} attempts", e, d.attempts + 1);
+                    self.pending.remove(i);
+                }
+                Err(e) => {
+                    let d = &mut self.pending[i];
+                    let backoff = FIRST_BACKOFF
+                        .saturating_mul(1 << d.attempts.min(16))
+                        .min(MAX_BACKOFF);
+                    warn!("{:#}, retrying in {:?}", e, backoff);
+                    d.attempts += 1;
+                    d.due = now_ms() + backoff.as_millis() as u64;
+                    blocked.push(d.url.clone());
+                    i += 1;
+                }
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    fn run(mut self, rx: Receiver<Delivery>) {
+        loop {
+            let received = match self.next_due() {
+                Some(due) => rx.recv_timeout(Duration::from_millis(due.saturating_sub(now_ms()))),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(delivery) => {
+                    self.pending.push(delivery);
+                    self.save();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // The daemon is exiting, what is pending is delivered after a restart
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.deliver_due();
+        }
+    }
+}
+
+/// Delivers webhook notifications on a thread of its own, with retries.
+/// Pending notifications are kept in a file, so that they survive restarts
+/// of the daemon.
+pub struct Outbox {
+    sender: Sender<Delivery>,
+}
+
+impl Outbox {
+    /// Must be called after daemonizing.
+    pub fn new(path: &Path, secret: Option<Vec<u8>>, retries: u32) -> Result<Outbox> {
+        let pending: Vec<Delivery> = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .context(format!("Failed to read webhook outbox {}", path.display()))?,
+            Err(_) => vec![],
+        };
+        if !pending.is_empty() {
+            info!("Resuming {} pending webhook notifications", pending.len());
+        }
+        let worker = Worker {
+            path: path.to_path_buf(),
+            pending,
+            secret,
+            retries,
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+        };
+        let (sender, rx) = channel();
+        std::thread::spawn(move || worker.run(rx));
+        Ok(Outbox { sender })
+    }
+
+    pub fn send(&self, url: &str, notification: &Notification) -> Result<()> {
+        let delivery = Delivery {
+            url: url.to_string(),
+            body: serde_json::to_string(notification)
+                .context("Failed to serialize notification")?,
+            attempts: 0,
+            due: now_ms(),
+        };
+        self.sender
+            .send(delivery)
+            .context("Webhook notifications are no longer delivered")
+    }
+}
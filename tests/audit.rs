@@ -0,0 +1,39 @@
+//! The audit log is rotated by size, and queried across rotated logs.
+mod common;
+
+use common::Daemon;
+use fwatchd::socket::*;
+
+#[test]
+fn rotated_logs_are_kept_and_queried() {
+    // Every record goes to a log of its own
+    let daemon = Daemon::start(&["--audit-max-size", "1", "--audit-keep", "2"]);
+    let client = daemon.client();
+    let fpaths: Vec<String> = (0..4)
+        .map(|n| {
+            let file = daemon.path(&format!("file{n}"));
+            daemon.track(&file, "one\n", Action::Save)
+        })
+        .collect();
+
+    let log = |suffix: &str| daemon.path(&format!("work/audit.log{suffix}"));
+    let logged = |suffix: &str| {
+        let rec: AuditRecord =
+            serde_json::from_str(&std::fs::read_to_string(log(suffix)).unwrap()).unwrap();
+        rec.path
+    };
+    assert_eq!(logged(""), fpaths[3]);
+    assert_eq!(logged(".1"), fpaths[2]);
+    assert_eq!(logged(".2"), fpaths[1]);
+    assert!(!log(".3").exists());
+
+    let records = client
+        .audit(&AuditQuery {
+            path: None,
+            since: None,
+            until: None,
+        })
+        .unwrap();
+    let paths: Vec<&String> = records.iter().map(|r| &r.path).collect();
+    assert_eq!(paths, [&fpaths[1], &fpaths[2], &fpaths[3]]);
+}
@@ -0,0 +1,82 @@
+//! Checkpoints, named by the user or taken by snapshotting all files.
+mod common;
+
+use common::Daemon;
+use fwatchd::socket::*;
+
+fn checkpoint(daemon: &Daemon, name: &str) {
+    daemon
+        .client()
+        .checkpoint(&NewCheckpoint {
+            name: name.to_string(),
+            files: vec![],
+            message: None,
+        })
+        .unwrap();
+}
+
+#[test]
+fn snapshot_all_keeps_existing_checkpoints() {
+    let daemon = Daemon::start(&[]);
+    daemon.track(&daemon.path("a"), "a1\n", Action::Save);
+    let client = daemon.client();
+
+    // Taken by the user under the names snapshots are given
+    let now = unix_now();
+    let names: Vec<String> = (now..now + 5).map(|t| format!("checkpoint-{t}")).collect();
+    for name in &names {
+        checkpoint(&daemon, name);
+    }
+    let mut taken = vec![];
+    for _ in 0..2 {
+        let snapshot = client
+            .snapshot(&ForceSnapshot {
+                fpath: None,
+                message: Some(String::from("all")),
+            })
+            .unwrap();
+        assert_eq!(snapshot.files.len(), 1);
+        taken.push(snapshot.checkpoint.unwrap());
+    }
+
+    let checkpoints = client.checkpoints().unwrap();
+    assert_eq!(checkpoints.len(), names.len() + 2, "{:?}", checkpoints);
+    for name in &names {
+        assert_eq!(checkpoints[name].message, None);
+    }
+    for name in &taken {
+        assert_eq!(checkpoints[name].message.as_deref(), Some("all"));
+    }
+}
+
+#[test]
+fn failed_snapshot_takes_none() {
+    let daemon = Daemon::start(&[]);
+    let client = daemon.client();
+    // Changes run the script rather than being saved
+    let a = daemon.path("a");
+    let fa = daemon.track(&a, "a1\n", Action::Script(String::from("/bin/true")));
+    std::fs::write(&a, "a2\n").unwrap();
+    let b = daemon.path("b");
+    daemon.track(&b, "b1\n", Action::Save);
+    std::fs::remove_file(&b).unwrap();
+
+    let err = client
+        .checkpoint(&NewCheckpoint {
+            name: String::from("partial"),
+            files: vec![],
+            message: None,
+        })
+        .unwrap_err();
+    assert!(err.to_string().contains("/b"), "{}", err);
+    let err = client
+        .snapshot(&ForceSnapshot {
+            fpath: None,
+            message: None,
+        })
+        .unwrap_err();
+    assert!(err.to_string().contains("/b"), "{}", err);
+
+    assert!(client.checkpoints().unwrap().is_empty());
+    assert_eq!(client.list(&fa).unwrap().len(), 1);
+}
@@ -0,0 +1,91 @@
+//! Results of the client, as typed values rather than messages.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+
+#[test]
+fn typed_results() {
+    let daemon = Daemon::start(&[]);
+    let client = daemon.client();
+    let file = daemon.path("file");
+    std::fs::write(&file, "one\n").unwrap();
+    let fpath = file.to_str().unwrap().to_string();
+
+    let first = client
+        .track(&Track {
+            fpath: fpath.clone(),
+            alias: Alias::Basename,
+            action: Action::Save,
+        })
+        .unwrap();
+    assert_eq!(first.path, fpath);
+    let items = client.list(&fpath).unwrap();
+    assert_eq!(items[0].hash, first.hash);
+
+    let item = client
+        .tag(&Tag {
+            fpath: fpath.clone(),
+            version: Version::Hash(first.hash.clone()),
+            add: vec![String::from("good")],
+            remove: vec![],
+            note: None,
+        })
+        .unwrap();
+    assert_eq!(
+        (&item.hash, &item.snapshot.labels[..]),
+        (&first.hash, &[String::from("good")][..])
+    );
+    let item = client
+        .pin(&Pin {
+            fpath: fpath.clone(),
+            version: Version::Hash(String::from("good")),
+            pinned: true,
+        })
+        .unwrap();
+    assert!(item.snapshot.pinned && item.current);
+
+    let taken = client
+        .checkpoint(&NewCheckpoint {
+            name: String::from("before"),
+            files: vec![],
+            message: None,
+        })
+        .unwrap();
+    assert_eq!(taken.checkpoint.as_deref(), Some("before"));
+    assert_eq!(taken.files.get(&fpath), Some(&first.hash));
+
+    std::fs::write(&file, "two\n").unwrap();
+    wait_until("Change saved", || client.list(&fpath).unwrap().len() == 2);
+    let selected = client
+        .select(&Select {
+            fpath: fpath.clone(),
+            version: Version::Hash(String::from("good")),
+            run_hooks: false,
+        })
+        .unwrap();
+    assert_eq!(selected, first);
+
+    let restored = client
+        .restore_checkpoint(&RestoreCheckpoint {
+            name: String::from("before"),
+            run_hooks: false,
+        })
+        .unwrap();
+    assert_eq!(restored, [first]);
+
+    let archive = daemon.path("archive.tar");
+    let tar = client
+        .export(&Export {
+            files: vec![fpath.clone()],
+            compress: false,
+        })
+        .unwrap();
+    std::fs::write(&archive, tar).unwrap();
+    let imported = client
+        .import(&Import {
+            path: archive.to_str().unwrap().to_string(),
+        })
+        .unwrap();
+    assert_eq!(imported.get(&fpath), Some(&2));
+}
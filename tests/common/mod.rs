@@ -0,0 +1,152 @@
+//! Runs a daemon in the foreground in a temporary directory.
+#![allow(dead_code)]
+use fwatchd::socket::{Action, Alias, Track};
+use fwatchd::Client;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// fwatchd in the foreground, with its working directory in `dir`.
+pub fn fwatchd(dir: &Path) -> Command {
+    fwatchd_exe(Path::new(env!("CARGO_BIN_EXE_fwatchd")), dir)
+}
+
+fn fwatchd_exe(exe: &Path, dir: &Path) -> Command {
+    let mut cmd = Command::new(exe);
+    cmd.arg("--foreground")
+        .arg("--working-directory")
+        .arg(dir.join("work"))
+        .env_remove("FWATCHD_SOCKET")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_PID")
+        .env_remove("NOTIFY_SOCKET")
+        .stdout(Stdio::null());
+    cmd
+}
+
+/// Poll until `done`, for up to 10s.
+pub fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "{} within 10s", what);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+pub fn stop(mut child: Child) {
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    child.wait().unwrap();
+}
+
+pub struct Daemon {
+    child: Option<Child>,
+    /// The user the daemon runs as, when not the one running the tests
+    uid: Option<u32>,
+    pub dir: TempDir,
+    pub socket: PathBuf,
+}
+
+impl Daemon {
+    pub fn start(args: &[&str]) -> Daemon {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("socket");
+        let mut daemon = Daemon {
+            child: None,
+            uid: None,
+            dir,
+            socket,
+        };
+        daemon.spawn(args);
+        daemon
+    }
+
+    /// Start the daemon as another user, which requires running as root.
+    /// The daemon is run off a copy, the build directory may not be
+    /// accessible to the user.
+    pub fn start_as(uid: u32, args: &[&str]) -> Daemon {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::chown(dir.path(), Some(uid), Some(uid)).unwrap();
+        std::fs::copy(env!("CARGO_BIN_EXE_fwatchd"), dir.path().join("fwatchd")).unwrap();
+        let socket = dir.path().join("socket");
+        let mut daemon = Daemon {
+            child: None,
+            uid: Some(uid),
+            dir,
+            socket,
+        };
+        daemon.spawn(args);
+        daemon
+    }
+
+    fn spawn(&mut self, args: &[&str]) {
+        let mut cmd = match self.uid {
+            Some(uid) => {
+                let mut cmd = fwatchd_exe(&self.path("fwatchd"), self.dir.path());
+                cmd.uid(uid).gid(uid);
+                cmd
+            }
+            None => fwatchd(self.dir.path()),
+        };
+        cmd.arg("--socket").arg(&self.socket).args(args);
+        let child = cmd.spawn().unwrap();
+        self.child = Some(child);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while UnixStream::connect(&self.socket).is_err() {
+            assert!(Instant::now() < deadline, "fwatchd did not start");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Stop the daemon and start it again on the same working directory.
+    pub fn restart(&mut self, args: &[&str]) {
+        if let Some(child) = self.child.take() {
+            stop(child);
+        }
+        self.spawn(args);
+    }
+
+    pub fn client(&self) -> Client {
+        Client::with_socket(&self.socket)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Write `content` to the file and track it, returning its path.
+    pub fn track(&self, file: &Path, content: &str, action: Action) -> String {
+        std::fs::write(file, content).unwrap();
+        let fpath = file.to_str().unwrap().to_string();
+        self.client()
+            .track(&Track {
+                fpath: fpath.clone(),
+                alias: Alias::Basename,
+                action,
+            })
+            .unwrap();
+        // The watch is added after answering, before the next request
+        self.client().status().unwrap();
+        fpath
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.as_ref().unwrap().id()
+    }
+
+    /// Whether the daemon is still running.
+    pub fn alive(&mut self) -> bool {
+        matches!(self.child.as_mut().map(|c| c.try_wait()), Some(Ok(None)))
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.take() {
+            stop(child);
+        }
+    }
+}
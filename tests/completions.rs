@@ -0,0 +1,85 @@
+//! Dynamic completion asks the daemon for tracked files and hashes.
+mod common;
+
+use common::Daemon;
+use fwatchd::socket::Action;
+use std::process::Command;
+
+/// Candidates for the last word, as completed by fish.
+fn complete(daemon: &Daemon, words: &[&str]) -> Vec<String> {
+    let out = Command::new(env!("CARGO_BIN_EXE_fwatchctl"))
+        .env("COMPLETE", "fish")
+        .env("FWATCHD_SOCKET", &daemon.socket)
+        .arg("--")
+        .arg("fwatchctl")
+        .args(words)
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| l.split('\t').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn completes_tracked_files_and_hashes() {
+    let daemon = Daemon::start(&[]);
+    let file = daemon.track(&daemon.path("file"), "content", Action::Save);
+    let file = file.as_str();
+    let client = daemon.client();
+
+    let prefix = &file[..file.len() - 2];
+    assert_eq!(complete(&daemon, &["select", "--file", prefix]), [file]);
+    assert!(complete(&daemon, &["select", "--file", "/nope"]).is_empty());
+
+    let hashes: Vec<String> = client
+        .list(file)
+        .unwrap()
+        .into_iter()
+        .map(|i| i.hash)
+        .collect();
+    assert!(!hashes.is_empty());
+    assert_eq!(
+        complete(&daemon, &["select", "-f", file, "--hash", ""]),
+        hashes
+    );
+    let prefix = &hashes[0][..4];
+    assert!(complete(&daemon, &["select", "--file", file, "-H", prefix])
+        .iter()
+        .all(|h| h.starts_with(prefix)));
+}
+
+#[test]
+fn completes_nothing_without_daemon() {
+    let daemon = Daemon::start(&[]);
+    let out = Command::new(env!("CARGO_BIN_EXE_fwatchctl"))
+        .env("COMPLETE", "fish")
+        .env("FWATCHD_SOCKET", daemon.path("missing"))
+        .args(["--", "fwatchctl", "select", "--file", ""])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn man_pages() {
+    let dir = tempfile::tempdir().unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_fwatchctl"))
+        .arg("man")
+        .arg(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = Command::new(env!("CARGO_BIN_EXE_fwatchd"))
+        .arg("--man")
+        .arg(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    for page in ["fwatchctl.1", "fwatchctl-select.1", "fwatchd.1"] {
+        assert!(dir.path().join(page).exists(), "{}", page);
+    }
+}
@@ -0,0 +1,153 @@
+//! Failures are reported with a kind, and by fwatchctl with an exit status.
+mod common;
+
+use common::{fwatchd, stop, wait_until, Daemon};
+use fwatchd::socket::*;
+use fwatchd::{Client, Error};
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::time::{Duration, Instant};
+
+fn fwatchctl(daemon: &Daemon, args: &[&str]) -> (i32, String) {
+    let out = process::Command::new(env!("CARGO_BIN_EXE_fwatchctl"))
+        .arg("--socket")
+        .arg(&daemon.socket)
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    (out.status.code().unwrap(), stderr)
+}
+
+#[test]
+fn errors_have_a_kind() {
+    let daemon = Daemon::start(&[]);
+    let client = daemon.client();
+
+    match client.list("/nope") {
+        Err(Error::Daemon(ErrorKind::NotTracked, msg)) => assert!(msg.contains("/nope")),
+        res => panic!("Expected not tracked, got {:?}", res),
+    }
+    match client.echo_err("boom") {
+        Err(Error::Daemon(ErrorKind::Failed, msg)) => assert!(msg.contains("boom")),
+        res => panic!("Expected failure, got {:?}", res),
+    }
+    // Not a string
+    match client.request(Command::List, &vec![0xffu8]) {
+        Err(Error::Daemon(ErrorKind::InvalidRequest, _)) => {}
+        res => panic!("Expected invalid request, got {:?}", res),
+    }
+}
+
+#[test]
+fn exit_status_by_class() {
+    let daemon = Daemon::start(&[]);
+    let file = daemon.path("file");
+    std::fs::write(&file, "content").unwrap();
+    let file = file.to_str().unwrap();
+
+    let (code, stderr) = fwatchctl(&daemon, &["list", "-f", "/nope"]);
+    assert_eq!(code, 5, "{}", stderr);
+    assert!(stderr.starts_with("fwatchctl: "), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+
+    assert_eq!(fwatchctl(&daemon, &["track", "-f", file]).0, 0);
+    let (code, stderr) = fwatchctl(&daemon, &["select", "-f", file, "-H", "feedbeef"]);
+    assert_eq!(code, 6, "{}", stderr);
+
+    let (code, _) = fwatchctl(&daemon, &["echo-err", "-m", "boom"]);
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn exit_status_when_not_running() {
+    let daemon = Daemon::start(&[]);
+    let socket = daemon.path("missing");
+    let out = process::Command::new(env!("CARGO_BIN_EXE_fwatchctl"))
+        .arg("--socket")
+        .arg(&socket)
+        .arg("status")
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("not running"), "{}", stderr);
+}
+
+#[test]
+fn stale_user_socket_is_not_used() {
+    let dir = tempfile::tempdir().unwrap();
+    // Left behind by a per-user daemon which is gone
+    let socket = dir.path().join("fwatchd.socket");
+    drop(UnixListener::bind(&socket).unwrap());
+
+    let out = process::Command::new(env!("CARGO_BIN_EXE_fwatchctl"))
+        .arg("status")
+        .env("XDG_RUNTIME_DIR", dir.path())
+        .env_remove("FWATCHD_SOCKET")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!stderr.contains(socket.to_str().unwrap()), "{}", stderr);
+    // Unless a system daemon is running
+    assert!(
+        out.status.success() || stderr.contains(SOCK_PATH),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn requests_time_out() {
+    let daemon = Daemon::start(&[]);
+    // Accepts connections, but never answers
+    let socket = daemon.path("silent.sock");
+    let _listener = UnixListener::bind(&socket).unwrap();
+
+    let client = Client::with_socket(&socket).with_timeout(Some(Duration::from_millis(200)));
+    let start = Instant::now();
+    match client.echo("hello") {
+        Err(Error::Io(e)) => assert!(
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ),
+            "{:?}",
+            e
+        ),
+        res => panic!("Expected a timeout, got {:?}", res),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn replaces_only_stale_sockets() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("socket");
+
+    // Nothing listens, but the path is not a socket
+    std::fs::write(&socket, "keep\n").unwrap();
+    let out = fwatchd(dir.path())
+        .arg("--socket")
+        .arg(&socket)
+        .stderr(process::Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("not a socket"), "{}", stderr);
+    assert_eq!(std::fs::read_to_string(&socket).unwrap(), "keep\n");
+
+    // Left behind by a daemon which is gone
+    std::fs::remove_file(&socket).unwrap();
+    drop(UnixListener::bind(&socket).unwrap());
+    let child = fwatchd(dir.path())
+        .arg("--socket")
+        .arg(&socket)
+        .spawn()
+        .unwrap();
+    wait_until("fwatchd listening", || {
+        Client::with_socket(&socket).echo("hello").is_ok()
+    });
+    stop(child);
+}
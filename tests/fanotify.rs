@@ -0,0 +1,71 @@
+//! Changes are attributed to the process which made them with fanotify,
+//! which requires CAP_SYS_ADMIN. Tests are skipped without it.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+use std::process::{Child, Command};
+
+fn available() -> bool {
+    let fd = unsafe { libc::fanotify_init(libc::FAN_CLASS_NOTIF, libc::O_RDONLY as u32) };
+    if fd < 0 {
+        eprintln!("Skipping, fanotify is not available");
+        return false;
+    }
+    unsafe { libc::close(fd) };
+    true
+}
+
+/// Write `content` from a shell which is kept around for a while, so that
+/// the daemon can still look it up.
+fn write_from_shell(file: &str, content: &str) -> Child {
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("echo {content} > {file}; sleep 5"))
+        .spawn()
+        .unwrap()
+}
+
+/// The modifier of the snapshot of the current content, once saved.
+fn modifier_of_current(daemon: &Daemon, fpath: &str, content: &str) -> Modifier {
+    let client = daemon.client();
+    let mut modifier = None;
+    wait_until("Change saved", || {
+        let items = client.list(fpath).unwrap();
+        modifier = items
+            .into_iter()
+            .find(|i| i.current && i.snapshot.modifier.is_some())
+            .and_then(|i| i.snapshot.modifier);
+        modifier.is_some() && std::fs::read_to_string(fpath).unwrap() == content
+    });
+    modifier.unwrap()
+}
+
+#[test]
+fn records_modifier_and_follows_replaced_files() {
+    if !available() {
+        return;
+    }
+    let daemon = Daemon::start(&["--fanotify"]);
+    let file = daemon.path("file");
+    let fpath = daemon.track(&file, "one\n", Action::Save);
+
+    let mut shell = write_from_shell(&fpath, "two");
+    let exe = std::fs::read_link(format!("/proc/{}/exe", shell.id())).unwrap();
+    let modifier = modifier_of_current(&daemon, &fpath, "two\n");
+    assert_eq!(modifier.pid, shell.id() as i32);
+    assert_eq!(modifier.exe.as_deref(), exe.to_str());
+    assert_eq!(modifier.uid, Some(unsafe { libc::getuid() }));
+    shell.kill().unwrap();
+    shell.wait().unwrap();
+
+    // Replaced by renaming over it, as editors do
+    let new = daemon.path("file.new");
+    std::fs::write(&new, "three\n").unwrap();
+    std::fs::rename(&new, &file).unwrap();
+    let mut shell = write_from_shell(&fpath, "four");
+    let modifier = modifier_of_current(&daemon, &fpath, "four\n");
+    assert_eq!(modifier.pid, shell.id() as i32);
+    shell.kill().unwrap();
+    shell.wait().unwrap();
+}
@@ -0,0 +1,56 @@
+//! Subscription and audit filters match whole path components, as the
+//! policy does.
+mod common;
+
+use common::Daemon;
+use fwatchd::socket::*;
+
+/// Track `{name}/file`.
+fn track(daemon: &Daemon, name: &str) -> String {
+    let dir = daemon.path(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    daemon.track(&dir.join("file"), "one\n", Action::Save)
+}
+
+#[test]
+fn audit_query_matches_components() {
+    let query = AuditQuery {
+        path: Some(String::from("/home/alice")),
+        ..AuditQuery::default()
+    };
+    let rec = |path| AuditRecord::new(path, AuditEvent::Track);
+    assert!(query.matches(&rec("/home/alice")));
+    assert!(query.matches(&rec("/home/alice/.bashrc")));
+    assert!(!query.matches(&rec("/home/alice2/.bashrc")));
+}
+
+#[test]
+fn audit_filter() {
+    let daemon = Daemon::start(&[]);
+    let alice = track(&daemon, "alice");
+    track(&daemon, "alice2");
+
+    let records = daemon
+        .client()
+        .audit(&AuditQuery {
+            path: Some(daemon.path("alice").to_str().unwrap().to_string()),
+            ..AuditQuery::default()
+        })
+        .unwrap();
+    assert!(!records.is_empty());
+    assert!(records.iter().all(|r| r.path == alice), "{:?}", records);
+}
+
+#[test]
+fn subscribe_filter() {
+    let daemon = Daemon::start(&[]);
+    let alice = track(&daemon, "alice");
+    let alice2 = track(&daemon, "alice2");
+
+    let filter = daemon.path("alice").to_str().unwrap().to_string();
+    let mut events = daemon.client().subscribe(&[filter]).unwrap();
+    std::fs::write(&alice2, "two\n").unwrap();
+    std::fs::write(&alice, "two\n").unwrap();
+    let event = events.next().unwrap().unwrap();
+    assert_eq!(event.path, alice);
+}
@@ -0,0 +1,95 @@
+//! Snapshots stored as commits in a git repository.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+use git2::Repository;
+use std::path::Path;
+
+/// Summaries of the commits in the repository, oldest first.
+fn commits(repo: &Path) -> Vec<String> {
+    let repo = Repository::open_bare(repo).unwrap();
+    let mut walk = repo.revwalk().unwrap();
+    walk.push_head().unwrap();
+    let mut summaries: Vec<String> = walk
+        .map(|id| {
+            let commit = repo.find_commit(id.unwrap()).unwrap();
+            commit.summary().unwrap().to_string()
+        })
+        .collect();
+    summaries.reverse();
+    summaries
+}
+
+#[test]
+fn one_commit_per_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    let daemon = Daemon::start(&["--git", repo.to_str().unwrap()]);
+    let client = daemon.client();
+    let file = daemon.path("file");
+    let fpath = daemon.track(&file, "one\n", Action::Save);
+    assert_eq!(commits(&repo), [format!("track {fpath}")]);
+
+    std::fs::write(&file, "two\n").unwrap();
+    wait_until("Change saved", || client.list(&fpath).unwrap().len() == 2);
+    let items = client.list(&fpath).unwrap();
+    assert!(items.iter().all(|i| i.snapshot.commit.is_some()));
+    let one = &items.iter().find(|i| !i.current).unwrap().hash;
+    let two = &items.iter().find(|i| i.current).unwrap().hash;
+    assert_eq!(
+        commits(&repo),
+        [format!("track {fpath}"), format!("change {fpath}")]
+    );
+
+    let show = |hash: &str| {
+        client
+            .show(&Cat {
+                fpath: fpath.clone(),
+                version: Version::Hash(hash.to_string()),
+            })
+            .unwrap()
+    };
+    assert_eq!(show(one), b"one\n");
+    assert_eq!(show(two), b"two\n");
+    let diff = client
+        .diff(&Diff {
+            fpath: fpath.clone(),
+            from: Version::Hash(one.clone()),
+            to: Some(Version::Hash(two.clone())),
+        })
+        .unwrap();
+    assert!(
+        diff.contains("-one\n") && diff.contains("+two\n"),
+        "{}",
+        diff
+    );
+
+    let select = |hash: &str| {
+        client
+            .select(&Select {
+                fpath: fpath.clone(),
+                version: Version::Hash(hash.to_string()),
+                run_hooks: false,
+            })
+            .unwrap();
+    };
+    select(one);
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "one\n");
+    let all = [
+        format!("track {fpath}"),
+        format!("change {fpath}"),
+        format!("restore {fpath}"),
+    ];
+    assert_eq!(commits(&repo), all);
+
+    // Nothing changes in the tree, so nothing is committed
+    select(one);
+    client
+        .snapshot(&ForceSnapshot {
+            fpath: Some(fpath.clone()),
+            message: None,
+        })
+        .unwrap();
+    assert_eq!(commits(&repo), all);
+}
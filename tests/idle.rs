@@ -0,0 +1,42 @@
+//! The event loop sleeps until there is something to do.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// User and system time of the process, in clock ticks.
+fn cpu_ticks(pid: u32) -> u64 {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+    // The command may contain spaces, fields are counted after it
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+}
+
+#[test]
+fn sleeps_when_idle() {
+    // Tracking reloads the watches, restart to wait as from startup
+    let mut daemon = Daemon::start(&[]);
+    daemon.track(&daemon.path("file"), "one\n", Action::Save);
+    daemon.restart(&[]);
+
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let before = cpu_ticks(daemon.pid());
+    std::thread::sleep(Duration::from_secs(2));
+    let used = cpu_ticks(daemon.pid()) - before;
+    assert!(used < ticks / 5, "{} ticks used in 2s while idle", used);
+}
+
+#[test]
+fn silent_client_does_not_stall_changes() {
+    let mut daemon = Daemon::start(&[]);
+    let file = daemon.path("file");
+    let fpath = daemon.track(&file, "one\n", Action::Save);
+    daemon.restart(&[]);
+    let client = daemon.client();
+
+    let _silent = UnixStream::connect(&daemon.socket).unwrap();
+    std::fs::write(&file, "two\n").unwrap();
+    wait_until("Change saved", || client.list(&fpath).unwrap().len() == 2);
+}
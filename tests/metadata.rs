@@ -0,0 +1,54 @@
+//! Restoring snapshots with the metadata they were taken with.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+/// Neither root nor the user running the tests.
+const UID: u32 = 4242;
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[test]
+fn restore_as_another_user() {
+    if !is_root() {
+        eprintln!("Skipping, changing user requires root");
+        return;
+    }
+    let daemon = Daemon::start_as(UID, &[]);
+    let client = daemon.client();
+
+    // Owned by root, and so recorded in the snapshot, but writable
+    let file = daemon.path("file");
+    std::fs::write(&file, "one\n").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o666)).unwrap();
+    let fpath = file.to_str().unwrap().to_string();
+    client
+        .track(&Track {
+            fpath: fpath.clone(),
+            alias: Alias::Basename,
+            action: Action::Save,
+        })
+        .unwrap();
+    let first = client.list(&fpath).unwrap().remove(0).hash;
+
+    std::fs::write(&file, "two\n").unwrap();
+    wait_until("Change saved", || client.list(&fpath).unwrap().len() == 2);
+
+    // The daemon can not give the restored file back to root, which must
+    // not keep it from being restored
+    client
+        .select(&Select {
+            fpath: fpath.clone(),
+            version: Version::Hash(first),
+            run_hooks: false,
+        })
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "one\n");
+    let meta = std::fs::metadata(&file).unwrap();
+    assert_eq!(meta.mode() & 0o7777, 0o666);
+    assert_eq!(meta.uid(), UID);
+}
@@ -0,0 +1,90 @@
+//! Metrics written for the textfile collector and served over HTTP.
+mod common;
+
+use common::Daemon;
+use fwatchd::socket::Action;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/// The value of a sample, e.g. `fwatchd_snapshots` or
+/// `fwatchd_requests_total{command="Track"}`.
+fn sample(text: &str, name: &str) -> Option<f64> {
+    text.lines()
+        .filter_map(|l| l.strip_prefix(name)?.strip_prefix(' '))
+        .find_map(|v| v.parse().ok())
+}
+
+/// Wait until `name` has reached `value` in the metrics returned by `read`.
+fn wait_for_sample(read: impl Fn() -> String, name: &str, value: f64) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let text = read();
+        if sample(&text, name) == Some(value) {
+            return text;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{} never became {}:\n{}",
+            name,
+            value,
+            text
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn textfile() {
+    let out = tempfile::tempdir().unwrap();
+    let prom = out.path().join("fwatchd.prom");
+    let daemon = Daemon::start(&["--metrics-file", prom.to_str().unwrap()]);
+    let read = || std::fs::read_to_string(&prom).unwrap_or_default();
+
+    let file = daemon.path("file");
+    daemon.track(&file, "one", Action::Save);
+    wait_for_sample(read, "fwatchd_requests_total{command=\"Track\"}", 1.0);
+
+    std::fs::write(&file, "two").unwrap();
+    let text = wait_for_sample(read, "fwatchd_snapshots", 2.0);
+    assert_eq!(
+        sample(&text, "fwatchd_events_total{type=\"written\"}"),
+        Some(1.0)
+    );
+    assert_eq!(sample(&text, "fwatchd_tracked_files"), Some(1.0));
+    assert_eq!(
+        sample(&text, "fwatchd_watches{state=\"active\"}"),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&text, "fwatchd_actions_total{kind=\"save\",result=\"run\"}"),
+        Some(1.0)
+    );
+    assert!(sample(&text, "fwatchd_store_bytes").unwrap() >= 6.0);
+    assert!(sample(&text, "fwatchd_index_save_seconds_count").unwrap() >= 2.0);
+    assert!(!out.path().join("fwatchd.prom.tmp").exists());
+}
+
+fn get(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {path} HTTP/1.0\r\nHost: {addr}\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn http() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let daemon = Daemon::start(&["--metrics-listen", &addr]);
+
+    daemon.track(&daemon.path("file"), "one", Action::Save);
+    let text = wait_for_sample(|| get(&addr, "/metrics"), "fwatchd_tracked_files", 1.0);
+    assert!(text.starts_with("HTTP/1.0 200"), "{}", text);
+    assert!(text.contains("# TYPE fwatchd_index_save_seconds histogram"));
+    assert!(get(&addr, "/nope").starts_with("HTTP/1.0 404"));
+}
@@ -0,0 +1,227 @@
+//! Fuzzing of the request decoder, whatever a client sends must be
+//! rejected or decoded without panicking.
+mod common;
+
+use common::Daemon;
+use fwatchd::socket::*;
+use serde::de::DeserializeOwned;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+/// xorshift64*, deterministic so that failures can be reproduced.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn bytes(&mut self, max: usize) -> Vec<u8> {
+        let len = self.below(max + 1);
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+const COMMANDS: [Command; 19] = [
+    Command::Echoerr,
+    Command::Echo,
+    Command::List,
+    Command::Track,
+    Command::Select,
+    Command::Subscribe,
+    Command::Audit,
+    Command::History,
+    Command::Cat,
+    Command::Tag,
+    Command::Pin,
+    Command::Snapshot,
+    Command::Checkpoint,
+    Command::Checkpoints,
+    Command::RestoreCheckpoint,
+    Command::Export,
+    Command::Import,
+    Command::Diff,
+    Command::Status,
+];
+
+fn packet<T: serde::Serialize>(command: Command, payload: &T) -> Vec<u8> {
+    bincode::serialize(&Packet {
+        command,
+        payload: bincode::serialize(payload).unwrap(),
+    })
+    .unwrap()
+}
+
+/// Well-formed requests to mutate.
+fn seeds() -> Vec<Vec<u8>> {
+    vec![
+        packet(Command::List, &String::from("*")),
+        packet(
+            Command::Track,
+            &Track {
+                fpath: String::from("/tmp/fuzz"),
+                alias: Alias::Script(String::from("/bin/true")),
+                action: Action::Save,
+            },
+        ),
+        packet(
+            Command::Select,
+            &Select {
+                fpath: String::from("/tmp/fuzz"),
+                version: Version::Hash(String::from("abc")),
+                run_hooks: true,
+            },
+        ),
+        packet(
+            Command::Diff,
+            &Diff {
+                fpath: String::from("/tmp/fuzz"),
+                from: Version::Number(2),
+                to: Some(Version::At(12)),
+            },
+        ),
+        packet(
+            Command::Checkpoint,
+            &NewCheckpoint {
+                name: String::from("fuzz"),
+                files: vec![String::from("/tmp/a"), String::from("/tmp/b")],
+                message: Some(String::from("m")),
+            },
+        ),
+        packet(Command::Audit, &AuditQuery::default()),
+        packet(Command::Subscribe, &vec![String::from("/tmp")]),
+    ]
+}
+
+fn decode_as<T: DeserializeOwned>(pkt: &Packet) {
+    let _ = pkt.decode::<T>();
+}
+
+/// Decode the payload as every request type, as the daemon and the policy
+/// would for some command.
+fn decode_all(bytes: &[u8]) {
+    let pkt = match Packet::read_from(bytes) {
+        Ok(pkt) => pkt,
+        Err(_) => return,
+    };
+    decode_as::<String>(&pkt);
+    decode_as::<Vec<String>>(&pkt);
+    decode_as::<Track>(&pkt);
+    decode_as::<Select>(&pkt);
+    decode_as::<Cat>(&pkt);
+    decode_as::<Diff>(&pkt);
+    decode_as::<Tag>(&pkt);
+    decode_as::<Pin>(&pkt);
+    decode_as::<ForceSnapshot>(&pkt);
+    decode_as::<NewCheckpoint>(&pkt);
+    decode_as::<RestoreCheckpoint>(&pkt);
+    decode_as::<Export>(&pkt);
+    decode_as::<Import>(&pkt);
+    decode_as::<AuditQuery>(&pkt);
+}
+
+fn mutate(rng: &mut Rng, seed: &[u8]) -> Vec<u8> {
+    let mut bytes = seed.to_vec();
+    for _ in 0..=rng.below(4) {
+        match rng.below(4) {
+            0 if !bytes.is_empty() => {
+                let i = rng.below(bytes.len());
+                bytes[i] ^= 1 << rng.below(8);
+            }
+            1 if !bytes.is_empty() => {
+                let i = rng.below(bytes.len());
+                bytes[i] = rng.next() as u8;
+            }
+            2 => bytes.truncate(rng.below(bytes.len() + 1)),
+            _ => bytes.extend(rng.bytes(16)),
+        }
+    }
+    bytes
+}
+
+#[test]
+fn random_bytes() {
+    let mut rng = Rng(0x5eed);
+    for _ in 0..20_000 {
+        decode_all(&rng.bytes(128));
+    }
+}
+
+#[test]
+fn mutated_requests() {
+    let mut rng = Rng(0xf00d);
+    let seeds = seeds();
+    for _ in 0..20_000 {
+        let seed = &seeds[rng.below(seeds.len())];
+        decode_all(&mutate(&mut rng, seed));
+    }
+}
+
+#[test]
+fn huge_lengths_are_rejected() {
+    // Command, then a payload claiming to be larger than any request
+    for len in [MAX_PACKET_SIZE + 1, u64::MAX / 2, u64::MAX] {
+        let mut bytes = 0u32.to_le_bytes().to_vec();
+        bytes.extend(len.to_le_bytes());
+        bytes.extend([0; 32]);
+        assert!(Packet::read_from(bytes.as_slice()).is_err());
+    }
+
+    // A string claiming to be larger than the payload
+    let pkt = Packet {
+        command: Command::List,
+        payload: u64::MAX.to_le_bytes().to_vec(),
+    };
+    assert!(pkt.decode::<String>().is_err());
+}
+
+#[test]
+fn valid_requests_decode() {
+    for seed in seeds() {
+        assert!(Packet::read_from(seed.as_slice()).is_ok());
+    }
+    let pkt = Packet::read_from(seeds()[1].as_slice()).unwrap();
+    let track = pkt.decode::<Track>().unwrap();
+    assert_eq!(track.fpath, "/tmp/fuzz");
+}
+
+/// The daemon keeps serving after any number of malformed requests.
+#[test]
+fn daemon_survives_malformed_requests() {
+    let mut daemon = Daemon::start(&["--client-timeout", "1"]);
+    let mut rng = Rng(0xbad);
+    let seeds = seeds();
+
+    for i in 0..300 {
+        let bytes = match i % 3 {
+            0 => rng.bytes(64),
+            1 => {
+                let seed = &seeds[rng.below(seeds.len())];
+                mutate(&mut rng, seed)
+            }
+            // A well-formed packet, with a payload of the wrong shape
+            _ => bincode::serialize(&Packet {
+                command: COMMANDS[rng.below(COMMANDS.len())].clone(),
+                payload: rng.bytes(48),
+            })
+            .unwrap(),
+        };
+        let mut stream = UnixStream::connect(&daemon.socket).unwrap();
+        let _ = stream.write_all(&bytes);
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+        // Subscriptions stay open, the response of others is irrelevant
+        let _ = stream.set_read_timeout(Some(std::time::Duration::from_millis(200)));
+        let _ = stream.read_to_end(&mut vec![]);
+    }
+
+    assert!(daemon.alive());
+    let status = daemon.client().status().unwrap();
+    assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+}
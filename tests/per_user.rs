@@ -0,0 +1,53 @@
+//! A per-user daemon keeps its socket and state in the XDG directories.
+mod common;
+
+use common::{stop, wait_until};
+use fwatchd::socket::*;
+use fwatchd::Client;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+
+#[test]
+fn uses_xdg_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    let runtime = dir.path().join("run");
+    let data = dir.path().join("data");
+    std::fs::create_dir(&runtime).unwrap();
+    let socket = runtime.join("fwatchd.socket");
+
+    let child = Command::new(env!("CARGO_BIN_EXE_fwatchd"))
+        .args(["--foreground", "--per-user"])
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .env("XDG_DATA_HOME", &data)
+        .env_remove("FWATCHD_SOCKET")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_PID")
+        .env_remove("NOTIFY_SOCKET")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_until("Daemon listening", || UnixStream::connect(&socket).is_ok());
+
+    // The only test in this file, nothing else reads the environment
+    std::env::remove_var("FWATCHD_SOCKET");
+    std::env::set_var("XDG_RUNTIME_DIR", &runtime);
+    let client = Client::new();
+    assert_eq!(client.socket(), socket);
+
+    let file = dir.path().join("file");
+    std::fs::write(&file, "one\n").unwrap();
+    let version = client
+        .track(&Track {
+            fpath: file.to_str().unwrap().to_string(),
+            alias: Alias::Basename,
+            action: Action::Save,
+        })
+        .unwrap();
+
+    let wdir = data.join("fwatch");
+    assert!(wdir.join("index").is_file());
+    assert!(wdir.join("audit.log").is_file());
+    let snapshot = format!("index.d{}-{}", file.to_str().unwrap(), version.hash);
+    assert_eq!(std::fs::read(wdir.join(snapshot)).unwrap(), b"one\n");
+    stop(child);
+}
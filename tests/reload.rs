@@ -0,0 +1,35 @@
+//! Reloading the index on SIGHUP.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+use fwatchd::Error;
+
+#[test]
+fn survives_changes_to_files_dropped_from_the_index() {
+    let mut daemon = Daemon::start(&[]);
+    let a = daemon.track(&daemon.path("a"), "a1\n", Action::Save);
+    let b = daemon.track(&daemon.path("b"), "b1\n", Action::Save);
+
+    // a is no longer tracked, but still watched
+    let index = daemon.path("work").join("index");
+    let mut state: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&index).unwrap()).unwrap();
+    state["files"].as_object_mut().unwrap().remove(&a).unwrap();
+    std::fs::write(&index, state.to_string()).unwrap();
+    unsafe { libc::kill(daemon.pid() as i32, libc::SIGHUP) };
+    wait_until("Index reloaded", || {
+        matches!(
+            daemon.client().list(&a),
+            Err(Error::Daemon(ErrorKind::NotTracked, _))
+        )
+    });
+
+    std::fs::write(&a, "a2\n").unwrap();
+    // Changes are handled in order, so a was handled once b is saved
+    std::fs::write(&b, "b2\n").unwrap();
+    wait_until("Change of b saved", || {
+        daemon.client().list(&b).unwrap().iter().any(|i| i.current)
+    });
+    assert!(daemon.alive());
+}
@@ -0,0 +1,67 @@
+//! Old snapshots are pruned beyond --max-snapshots, unless kept on purpose.
+mod common;
+
+use common::{wait_until, Daemon};
+use fwatchd::socket::*;
+
+#[test]
+fn prunes_oldest_unpinned_snapshots() {
+    let daemon = Daemon::start(&["--max-snapshots", "3"]);
+    let client = daemon.client();
+    let file = daemon.path("file");
+    let fpath = daemon.track(&file, "v0\n", Action::Save);
+
+    let mut hashes = vec![];
+    for v in 0..6 {
+        if v > 0 {
+            std::fs::write(&file, format!("v{v}\n")).unwrap();
+            wait_until("Change saved", || {
+                client.history(&fpath).unwrap().len() == v + 1
+            });
+        }
+        hashes.push(client.history(&fpath).unwrap().last().unwrap().hash.clone());
+        match v {
+            0 => {
+                client
+                    .pin(&Pin {
+                        fpath: fpath.clone(),
+                        version: Version::Hash(hashes[0].clone()),
+                        pinned: true,
+                    })
+                    .unwrap();
+            }
+            1 => {
+                client
+                    .checkpoint(&NewCheckpoint {
+                        name: String::from("v1"),
+                        files: vec![fpath.clone()],
+                        message: None,
+                    })
+                    .unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    // v2 and v3 are the oldest which are neither pinned, checkpointed nor
+    // current, three unpinned ones are left
+    let mut kept: Vec<String> = client
+        .list(&fpath)
+        .unwrap()
+        .into_iter()
+        .map(|i| i.hash)
+        .collect();
+    kept.sort_by_key(|h| hashes.iter().position(|x| x == h));
+    assert_eq!(
+        kept,
+        [0, 1, 4, 5].map(|v| hashes[v].clone()),
+        "of {:?}",
+        hashes
+    );
+    let current = client.list(&fpath).unwrap();
+    assert!(current.iter().any(|i| i.current && i.hash == hashes[5]));
+    for (v, hash) in hashes.iter().enumerate() {
+        let snapshot = daemon.path(&format!("work/index.d{}-{}", fpath, hash));
+        assert_eq!(snapshot.exists(), ![2, 3].contains(&v), "v{}", v);
+    }
+}
@@ -0,0 +1,155 @@
+//! The daemon as started by systemd, with a fake notify socket standing in
+//! for the service manager.
+mod common;
+
+use common::{fwatchd, stop, wait_until};
+use fwatchd::socket::*;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Notifications until one containing `state` is received.
+fn wait_for_state(notify: &UnixDatagram, state: &str) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut received = vec![];
+    let mut buf = [0; 4096];
+    while Instant::now() < deadline {
+        let n = match notify.recv(&mut buf) {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let msg = String::from_utf8_lossy(&buf[..n]).into_owned();
+        let done = msg.lines().any(|l| l == state);
+        received.push(msg);
+        if done {
+            return received;
+        }
+    }
+    panic!("No {} within 10s, got {:?}", state, received);
+}
+
+fn notify_socket(dir: &Path) -> UnixDatagram {
+    let notify = UnixDatagram::bind(dir.join("notify")).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    notify
+}
+
+#[test]
+fn notifies_ready_watchdog_and_stopping() {
+    let dir = tempfile::tempdir().unwrap();
+    let notify = notify_socket(dir.path());
+
+    let child = fwatchd(dir.path())
+        .arg("--socket")
+        .arg(dir.path().join("socket"))
+        .env("NOTIFY_SOCKET", dir.path().join("notify"))
+        .env("WATCHDOG_USEC", "200000")
+        .spawn()
+        .unwrap();
+
+    let ready = wait_for_state(&notify, "READY=1");
+    assert!(ready.last().unwrap().contains("STATUS=Watching 0 files"));
+    wait_for_state(&notify, "WATCHDOG=1");
+
+    let status = fwatchd::Client::with_socket(dir.path().join("socket"))
+        .status()
+        .unwrap();
+    assert_eq!(status.pid, child.id());
+
+    stop(child);
+    wait_for_state(&notify, "STOPPING=1");
+}
+
+/// Start the daemon with a listening socket on fd 3 and the environment
+/// set by the `sh` assignments in `vars`, in which `$$` is the daemon.
+fn activated(dir: &Path, socket: &Path, vars: &str, args: &[&str]) -> Child {
+    let listener = UnixListener::bind(socket).unwrap();
+    let fd = listener.as_raw_fd();
+
+    // The shell becomes the daemon by exec
+    let cmd = fwatchd(dir);
+    let mut sh = Command::new("sh");
+    sh.arg("-c")
+        .arg(format!("{vars} exec \"$@\""))
+        .arg("sh")
+        .arg(cmd.get_program())
+        .args(cmd.get_args())
+        .args(args)
+        .env_remove("FWATCHD_SOCKET")
+        .env("NOTIFY_SOCKET", dir.join("notify"))
+        .stdout(Stdio::null());
+    unsafe {
+        sh.pre_exec(move || {
+            if libc::dup2(fd, 3) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    sh.spawn().unwrap()
+}
+
+#[test]
+fn accepts_socket_from_listen_fds() {
+    let dir = tempfile::tempdir().unwrap();
+    let notify = notify_socket(dir.path());
+    let socket = dir.path().join("activated");
+    let child = activated(dir.path(), &socket, "LISTEN_PID=$$ LISTEN_FDS=1", &[]);
+
+    wait_for_state(&notify, "READY=1");
+    // The daemon listens on the socket it was given, not on one of its own
+    let client = fwatchd::Client::with_socket(&socket);
+    let status = client.status().unwrap();
+    assert_eq!(status.pid, child.id());
+
+    // Scripts are not passed the socket, nor told it is theirs
+    let hook = dir.path().join("hook");
+    let env = dir.path().join("env");
+    std::fs::write(&hook, format!("#!/bin/sh\nenv > {}\n", env.display())).unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let file = dir.path().join("file");
+    std::fs::write(&file, "one\n").unwrap();
+    client
+        .track(&Track {
+            fpath: file.to_str().unwrap().to_string(),
+            alias: Alias::Basename,
+            action: Action::Script(hook.to_str().unwrap().to_string()),
+        })
+        .unwrap();
+    // The watch is added after answering, before the next request
+    client.status().unwrap();
+    std::fs::write(&file, "two\n").unwrap();
+    wait_until("Script run", || {
+        std::fs::read_to_string(&env).is_ok_and(|e| e.contains("FWATCHD_EVENT"))
+    });
+    let env = std::fs::read_to_string(&env).unwrap();
+    assert!(!env.contains("LISTEN_"), "{}", env);
+
+    stop(child);
+}
+
+#[test]
+fn ignores_listen_fds_without_listen_pid() {
+    let dir = tempfile::tempdir().unwrap();
+    let notify = notify_socket(dir.path());
+    let socket = dir.path().join("activated");
+    let own = dir.path().join("own");
+    let child = activated(
+        dir.path(),
+        &socket,
+        "LISTEN_FDS=1",
+        &["--socket", own.to_str().unwrap()],
+    );
+
+    wait_for_state(&notify, "READY=1");
+    let status = fwatchd::Client::with_socket(&own).status().unwrap();
+    assert_eq!(status.pid, child.id());
+
+    stop(child);
+}
@@ -0,0 +1,130 @@
+//! Webhook notifications, delivered to a local HTTP stand-in for the
+//! receiving system.
+mod common;
+
+use common::{wait_until, Daemon};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use fwatchd::socket::{Action, Notification};
+use std::net::TcpListener;
+use std::time::Duration;
+
+struct Received {
+    body: String,
+    signature: Option<String>,
+    delivery: String,
+}
+
+fn header(req: &tiny_http::Request, name: &str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.to_string())
+}
+
+/// Receive one request, answering with `status`.
+fn receive(server: &tiny_http::Server, status: u16) -> Received {
+    let mut req = server
+        .recv_timeout(Duration::from_secs(10))
+        .unwrap()
+        .expect("No notification within 10s");
+    assert_eq!(req.method(), &tiny_http::Method::Post);
+    let mut body = String::new();
+    req.as_reader().read_to_string(&mut body).unwrap();
+    let received = Received {
+        body,
+        signature: header(&req, "X-Fwatchd-Signature"),
+        delivery: header(&req, "X-Fwatchd-Delivery").unwrap(),
+    };
+    req.respond(tiny_http::Response::empty(status)).unwrap();
+    received
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[test]
+fn signed_notification() {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", server.server_addr());
+    let secrets = tempfile::tempdir().unwrap();
+    let secret = secrets.path().join("secret");
+    std::fs::write(&secret, "s3cret\n").unwrap();
+    let daemon = Daemon::start(&["--webhook-secret-file", secret.to_str().unwrap()]);
+    let client = daemon.client();
+
+    let file = daemon.path("file");
+    daemon.track(&file, "one\ntwo\n", Action::Webhook(url.clone()));
+    std::fs::write(&file, "one\nTWO\nthree\n").unwrap();
+
+    let received = receive(&server, 200);
+    let mut mac = Hmac::new(Sha256::new(), b"s3cret");
+    mac.input(received.body.as_bytes());
+    let expected: String = mac
+        .result()
+        .code()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    assert_eq!(received.signature, Some(format!("sha256={expected}")));
+
+    let n: Notification = serde_json::from_str(&received.body).unwrap();
+    let items = client.list(file.to_str().unwrap()).unwrap();
+    let current = items.iter().find(|i| i.current).unwrap();
+    assert_eq!(n.event, "change");
+    assert_eq!(n.path, file.to_str().unwrap());
+    assert_eq!(n.new_hash.as_ref(), Some(&current.hash));
+    assert!(n.old_hash.is_some());
+    assert_ne!(n.old_hash, n.new_hash);
+    let diff = n.diff.unwrap();
+    assert_eq!((diff.added, diff.removed), (2, 1));
+    assert!(!n.host.is_empty());
+    assert!(n.time > 0);
+}
+
+#[test]
+fn retried_until_delivered() {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", server.server_addr());
+    let daemon = Daemon::start(&[]);
+
+    let file = daemon.path("file");
+    daemon.track(&file, "one\ntwo\n", Action::Webhook(url.clone()));
+    std::fs::write(&file, "changed\n").unwrap();
+
+    let first = receive(&server, 503);
+    assert_eq!(first.signature, None);
+    let second = receive(&server, 200);
+    assert_eq!(first.body, second.body);
+    assert_eq!(first.delivery, second.delivery);
+}
+
+#[test]
+fn outbox_survives_restart() {
+    let addr = free_addr();
+    let url = format!("http://{addr}/hook");
+    let mut daemon = Daemon::start(&[]);
+    let outbox = daemon.path("work").join("outbox");
+
+    // Nothing is listening, the notification stays in the outbox
+    let file = daemon.path("file");
+    daemon.track(&file, "one\ntwo\n", Action::Webhook(url.clone()));
+    std::fs::write(&file, "changed\n").unwrap();
+    wait_until("Notification queued", || {
+        std::fs::read_to_string(&outbox)
+            .unwrap_or_default()
+            .contains(&url)
+    });
+
+    let server = tiny_http::Server::http(&addr).unwrap();
+    daemon.restart(&[]);
+    let received = receive(&server, 200);
+    let n: Notification = serde_json::from_str(&received.body).unwrap();
+    assert_eq!(n.path, file.to_str().unwrap());
+    wait_until("Outbox emptied", || {
+        std::fs::read_to_string(&outbox).unwrap_or_default() == "[]"
+    });
+}